@@ -0,0 +1,74 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use strange_cipher::{
+    attractor::{self, AttractorKind},
+    lattice::CoupledMapLattice,
+    parallel::ParallelKeyStream,
+};
+
+const SECRET: [u8; 32] = [42; 32];
+
+fn attractor_key_stream(kind: AttractorKind, len: usize) -> Vec<u8> {
+    let attractor = kind.build(&SECRET);
+    let mut state = attractor.initial_state();
+    let mut key_stream = Vec::with_capacity(len + 16);
+    while key_stream.len() < len {
+        state = attractor.step(&state);
+        key_stream.extend(attractor::key_bytes(attractor.as_ref(), &state));
+    }
+    key_stream.truncate(len);
+    key_stream
+}
+
+fn key_stream(c: &mut Criterion) {
+    let mut group = c.benchmark_group("key_stream");
+
+    for len in [1 << 10, 1 << 16, 1 << 20] {
+        group.throughput(Throughput::Bytes(len as u64));
+
+        for kind in [AttractorKind::Lorenz, AttractorKind::Logistic] {
+            group.bench_with_input(BenchmarkId::new(kind.to_string(), len), &len, |b, &len| {
+                b.iter(|| attractor_key_stream(kind, black_box(len)))
+            });
+        }
+        group.bench_with_input(BenchmarkId::new("lattice", len), &len, |b, &len| {
+            b.iter(|| {
+                CoupledMapLattice::from_state(&SECRET, &[-10.0, -7.0, 35.0])
+                    .key_stream(black_box(len))
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn parallel_key_stream(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parallel_key_stream");
+    let len = 1 << 22;
+    group.throughput(Throughput::Bytes(len as u64));
+    group.sample_size(10);
+
+    let attractor = AttractorKind::Lorenz.build(&SECRET);
+    for trajectories in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::new("lorenz", trajectories),
+            &trajectories,
+            |b, &trajectories| {
+                b.iter(|| {
+                    ParallelKeyStream::new(
+                        attractor.as_ref(),
+                        &SECRET,
+                        &[-10.0, -7.0, 35.0],
+                        trajectories,
+                    )
+                    .key_stream(black_box(len))
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, key_stream, parallel_key_stream);
+criterion_main!(benches);
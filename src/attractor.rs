@@ -0,0 +1,375 @@
+use std::fmt;
+
+use crate::{
+    common,
+    maps::{ArnoldCat, Henon, Logistic, Tent},
+    scheme::SyncScheme,
+};
+
+/// A chaotic system stepped in discrete time, over a state of any dimension
+pub trait Attractor: Send + Sync {
+    fn dimension(&self) -> usize;
+
+    /// Where both sides start before they are driven into sync
+    fn initial_state(&self) -> Vec<f64>;
+
+    /// Advances the state by one step
+    fn step(&self, state: &[f64]) -> Vec<f64>;
+
+    /// How much time one step covers, so couplings can be given as rates
+    fn step_size(&self) -> f64;
+
+    /// Coordinates the key stream is drawn from
+    fn key_coordinates(&self) -> &'static [usize];
+}
+
+/// Pecora-Carroll step: the first coordinate is replaced by the drive before stepping
+pub fn driven_step(attractor: &dyn Attractor, state: &[f64], drive: f64) -> Vec<f64> {
+    let mut driven = state.to_vec();
+    driven[0] = drive;
+    attractor.step(&driven)
+}
+
+pub fn key_bytes(attractor: &dyn Attractor, state: &[f64]) -> Vec<u8> {
+    attractor
+        .key_coordinates()
+        .iter()
+        .flat_map(|&i| state[i].to_ne_bytes())
+        .collect()
+}
+
+/// Estimates the Lyapunov exponents (largest first, per unit of time) by following
+/// a set of tangent vectors along the orbit and re-orthonormalising them every step
+pub fn lyapunov_spectrum(attractor: &dyn Attractor, state: &[f64], steps: usize) -> Vec<f64> {
+    const EPSILON: f64 = 1e-8;
+
+    let n = attractor.dimension();
+    let mut state = state.to_vec();
+    let mut basis: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+    let mut sums = vec![0.0; n];
+
+    for _ in 0..steps {
+        let next = attractor.step(&state);
+
+        for vector in basis.iter_mut() {
+            let nudged: Vec<f64> = state
+                .iter()
+                .zip(vector.iter())
+                .map(|(s, v)| s + EPSILON * v)
+                .collect();
+            *vector = attractor
+                .step(&nudged)
+                .iter()
+                .zip(next.iter())
+                .map(|(a, b)| (a - b) / EPSILON)
+                .collect();
+        }
+
+        // Gram-Schmidt
+        for i in 0..n {
+            for j in 0..i {
+                let projection: f64 = basis[i]
+                    .iter()
+                    .zip(basis[j].iter())
+                    .map(|(a, b)| a * b)
+                    .sum();
+                let (done, rest) = basis.split_at_mut(i);
+                rest[0]
+                    .iter_mut()
+                    .zip(done[j].iter())
+                    .for_each(|(a, b)| *a -= projection * b);
+            }
+            let norm = basis[i].iter().map(|a| a * a).sum::<f64>().sqrt();
+            basis[i].iter_mut().for_each(|a| *a /= norm);
+            sums[i] += norm.ln();
+        }
+
+        state = next;
+    }
+
+    let time = steps as f64 * attractor.step_size();
+    sums.iter().map(|sum| sum / time).collect()
+}
+
+pub struct Lorenz {
+    pub sigma: f64,
+    pub rho: f64,
+    pub beta: f64,
+    pub h: f64,
+}
+
+impl Attractor for Lorenz {
+    fn dimension(&self) -> usize {
+        3
+    }
+
+    fn initial_state(&self) -> Vec<f64> {
+        vec![-10.0, -7.0, 35.0]
+    }
+
+    fn step(&self, state: &[f64]) -> Vec<f64> {
+        let (x, y, z) = common::lorenz_attractor(
+            state[0], None, state[1], state[2], self.sigma, self.rho, self.beta, self.h,
+        );
+        vec![x, y, z]
+    }
+
+    fn step_size(&self) -> f64 {
+        self.h
+    }
+
+    fn key_coordinates(&self) -> &'static [usize] {
+        &[1]
+    }
+}
+
+/// Wang and Wang's hyperchaotic Lorenz system: a fourth coordinate `w` fed back into
+/// `x` gives it two positive Lyapunov exponents. Euler steps only stay on the attractor
+/// with a much smaller `h` than Lorenz needs
+pub struct HyperLorenz {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub r: f64,
+    pub h: f64,
+}
+
+impl Attractor for HyperLorenz {
+    fn dimension(&self) -> usize {
+        4
+    }
+
+    fn initial_state(&self) -> Vec<f64> {
+        vec![-10.0, -7.0, 35.0, 10.0]
+    }
+
+    fn step(&self, state: &[f64]) -> Vec<f64> {
+        let (x, y, z, w) = (state[0], state[1], state[2], state[3]);
+
+        vec![
+            x + (self.a * (y - x) + w) * self.h,
+            y + (self.c * x - y - x * z) * self.h,
+            z + (x * y - self.b * z) * self.h,
+            w + (-y * z + self.r * w) * self.h,
+        ]
+    }
+
+    fn step_size(&self) -> f64 {
+        self.h
+    }
+
+    fn key_coordinates(&self) -> &'static [usize] {
+        &[1, 3]
+    }
+}
+
+/// The systems a session can pick, with their parameters drawn from the agreed secret.
+/// The discrete maps (`maps`) are far cheaper to step than the integrated flows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttractorKind {
+    Lorenz = 0,
+    HyperLorenz = 1,
+    Logistic = 2,
+    Tent = 3,
+    Henon = 4,
+    ArnoldCat = 5,
+}
+
+impl AttractorKind {
+    pub fn from_byte(byte: u8) -> Option<AttractorKind> {
+        match byte {
+            0 => Some(AttractorKind::Lorenz),
+            1 => Some(AttractorKind::HyperLorenz),
+            2 => Some(AttractorKind::Logistic),
+            3 => Some(AttractorKind::Tent),
+            4 => Some(AttractorKind::Henon),
+            5 => Some(AttractorKind::ArnoldCat),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<AttractorKind> {
+        match name {
+            "lorenz" => Some(AttractorKind::Lorenz),
+            "hyper-lorenz" => Some(AttractorKind::HyperLorenz),
+            "logistic" => Some(AttractorKind::Logistic),
+            "tent" => Some(AttractorKind::Tent),
+            "henon" => Some(AttractorKind::Henon),
+            "arnold-cat" => Some(AttractorKind::ArnoldCat),
+            _ => None,
+        }
+    }
+
+    pub fn is_discrete(&self) -> bool {
+        !matches!(self, AttractorKind::Lorenz | AttractorKind::HyperLorenz)
+    }
+
+    /// Whether a receiver coupled with `scheme` syncs to this system. The couplings
+    /// given as rates only make sense for the flows, where a step is a short slice of
+    /// time; the cat map stretches every error and cannot be synced at all
+    pub fn syncs_with(&self, scheme: &SyncScheme) -> bool {
+        match self {
+            AttractorKind::Lorenz | AttractorKind::HyperLorenz => true,
+            AttractorKind::Logistic | AttractorKind::Tent | AttractorKind::Henon => {
+                *scheme == SyncScheme::Replacement
+            }
+            AttractorKind::ArnoldCat => false,
+        }
+    }
+
+    pub fn build(&self, secret: &[u8; 32]) -> Box<dyn Attractor> {
+        match self {
+            AttractorKind::Lorenz => {
+                let (rho, sigma) = common::derive_parameters(secret);
+                Box::new(Lorenz {
+                    sigma,
+                    rho,
+                    beta: 8.0 / 3.0,
+                    h: 0.01,
+                })
+            }
+            AttractorKind::HyperLorenz => Box::new(HyperLorenz {
+                a: 10.0,
+                b: 8.0 / 3.0,
+                c: 28.0,
+                // closer to 0 it stays hyperchaotic but takes far longer to sync
+                r: common::lin_interp(secret[10] as f64, 0.0, -1.4, 255.0, -0.8),
+                h: 0.001,
+            }),
+            // every parameter in these ranges was checked to be chaotic, the maps are
+            // riddled with periodic windows just outside them
+            AttractorKind::Logistic => Box::new(Logistic {
+                r: common::lin_interp(secret[10] as f64, 0.0, 3.999, 255.0, 4.0),
+            }),
+            AttractorKind::Tent => Box::new(Tent {
+                mu: common::lin_interp(secret[10] as f64, 0.0, 1.9, 255.0, 1.999),
+            }),
+            AttractorKind::Henon => Box::new(Henon {
+                a: common::lin_interp(secret[10] as f64, 0.0, 1.41, 255.0, 1.42),
+                b: 0.3,
+            }),
+            AttractorKind::ArnoldCat => Box::new(ArnoldCat {
+                p: (1 + secret[10] % 4) as f64,
+                q: (1 + secret[10] / 64) as f64,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for AttractorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttractorKind::Lorenz => write!(f, "lorenz"),
+            AttractorKind::HyperLorenz => write!(f, "hyper-lorenz"),
+            AttractorKind::Logistic => write!(f, "logistic"),
+            AttractorKind::Tent => write!(f, "tent"),
+            AttractorKind::Henon => write!(f, "henon"),
+            AttractorKind::ArnoldCat => write!(f, "arnold-cat"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn settled(attractor: &dyn Attractor) -> Vec<f64> {
+        let mut state = attractor.initial_state();
+        for _ in 0..5_000 {
+            state = attractor.step(&state);
+        }
+        state
+    }
+
+    #[test]
+    fn test_lorenz_matches_the_tuple_implementation() {
+        let attractor = AttractorKind::Lorenz.build(&[77; 32]);
+        let (rho, sigma) = common::derive_parameters(&[77; 32]);
+
+        let mut state = attractor.initial_state();
+        let mut tuple = (-10.0, -7.0, 35.0);
+        for _ in 0..1_000 {
+            state = attractor.step(&state);
+            tuple = common::lorenz_attractor(
+                tuple.0,
+                None,
+                tuple.1,
+                tuple.2,
+                sigma,
+                rho,
+                8.0 / 3.0,
+                0.01,
+            );
+        }
+        assert_eq!(state, vec![tuple.0, tuple.1, tuple.2]);
+
+        let driven = driven_step(attractor.as_ref(), &state, 3.5);
+        let tuple = common::lorenz_attractor(
+            tuple.0,
+            Some(3.5),
+            tuple.1,
+            tuple.2,
+            sigma,
+            rho,
+            8.0 / 3.0,
+            0.01,
+        );
+        assert_eq!(driven, vec![tuple.0, tuple.1, tuple.2]);
+    }
+
+    #[test]
+    fn test_lorenz_has_one_positive_exponent() {
+        let attractor = AttractorKind::Lorenz.build(&[0; 32]);
+        let spectrum = lyapunov_spectrum(attractor.as_ref(), &settled(attractor.as_ref()), 50_000);
+
+        assert!(spectrum[0] > 0.3, "{:?}", spectrum);
+        assert!(spectrum[1].abs() < 0.1, "{:?}", spectrum);
+        assert!(spectrum[2] < -5.0, "{:?}", spectrum);
+    }
+
+    #[test]
+    fn test_hyper_lorenz_is_hyperchaotic() {
+        for secret_byte in [0u8, 255] {
+            let attractor = AttractorKind::HyperLorenz.build(&[secret_byte; 32]);
+            let spectrum =
+                lyapunov_spectrum(attractor.as_ref(), &settled(attractor.as_ref()), 100_000);
+
+            assert_eq!(
+                spectrum.iter().filter(|&&exponent| exponent > 0.05).count(),
+                2,
+                "{:?}",
+                spectrum
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_bytes_come_from_every_key_coordinate() {
+        let attractor = AttractorKind::HyperLorenz.build(&[0; 32]);
+        let state = vec![1.0, 2.0, 3.0, 4.0];
+
+        let bytes = key_bytes(attractor.as_ref(), &state);
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(bytes[..8], 2.0f64.to_ne_bytes());
+        assert_eq!(bytes[8..], 4.0f64.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_kind_names_and_bytes() {
+        for kind in [
+            AttractorKind::Lorenz,
+            AttractorKind::HyperLorenz,
+            AttractorKind::Logistic,
+            AttractorKind::Tent,
+            AttractorKind::Henon,
+            AttractorKind::ArnoldCat,
+        ] {
+            assert_eq!(AttractorKind::from_byte(kind as u8), Some(kind));
+            assert_eq!(AttractorKind::from_name(&kind.to_string()), Some(kind));
+        }
+        assert_eq!(AttractorKind::from_byte(9), None);
+        assert_eq!(AttractorKind::from_name("rossler"), None);
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashSet;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    nonce::{Nonce, NONCE_LEN},
+    stream::BulkKeyStream,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const CHUNKED_MESSAGE: u8 = 10;
+pub const CHUNK: u8 = 11;
+pub const CHUNKS_SENT: u8 = 12;
+pub const MISSING_CHUNKS: u8 = 13;
+
+/// Largest message the receiver accepts in chunks
+pub const MAX_MESSAGE_LEN: u64 = 1 << 24;
+const MAC_LEN: usize = 32;
+
+/// Announces a message that follows in chunks, and the key stream and nonce it
+/// was encrypted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkedHeader {
+    /// The message ID its receipt names
+    pub id: u64,
+    pub total_len: u64,
+    pub chunk_count: u32,
+    pub nonce: Nonce,
+    pub key_stream: BulkKeyStream,
+}
+
+impl ChunkedHeader {
+    pub fn new(
+        id: u64,
+        total_len: usize,
+        chunk_size: usize,
+        nonce: Nonce,
+        key_stream: BulkKeyStream,
+    ) -> ChunkedHeader {
+        ChunkedHeader {
+            id,
+            total_len: total_len as u64,
+            chunk_count: total_len.div_ceil(chunk_size) as u32,
+            nonce,
+            key_stream,
+        }
+    }
+
+    // [CHUNKED_MESSAGE] [id: u64] [total_len: u64] [chunk_count: u32] [nonce; 16]
+    // [key stream]
+    fn fields(&self) -> Vec<u8> {
+        let mut bytes = vec![CHUNKED_MESSAGE];
+        bytes.extend_from_slice(&self.id.to_le_bytes());
+        bytes.extend_from_slice(&self.total_len.to_le_bytes());
+        bytes.extend_from_slice(&self.chunk_count.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.key_stream.to_bytes());
+        bytes
+    }
+
+    // [fields] [mac; 32]
+    pub fn to_bytes(&self, key: &[u8; 32]) -> Vec<u8> {
+        let mut bytes = self.fields();
+        let mac = header_mac(key, &bytes).finalize().into_bytes();
+        bytes.extend_from_slice(&mac);
+        bytes
+    }
+
+    /// Checks the MAC before anything in the header is trusted, the length above all
+    pub fn from_bytes(bytes: &[u8], key: &[u8; 32]) -> Option<ChunkedHeader> {
+        if bytes.len() < 21 + NONCE_LEN + MAC_LEN || bytes[0] != CHUNKED_MESSAGE {
+            return None;
+        }
+        let (bytes, mac) = bytes.split_at(bytes.len() - MAC_LEN);
+        header_mac(key, bytes).verify_slice(mac).ok()?;
+
+        let id = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+        let total_len = u64::from_le_bytes(bytes[9..17].try_into().unwrap());
+        let chunk_count = u32::from_le_bytes(bytes[17..21].try_into().unwrap());
+        // every chunk but an empty message's one carries at least a byte
+        if total_len > MAX_MESSAGE_LEN || chunk_count as u64 > total_len.max(1) {
+            return None;
+        }
+
+        Some(ChunkedHeader {
+            id,
+            total_len,
+            chunk_count,
+            nonce: bytes[21..21 + NONCE_LEN].try_into().unwrap(),
+            key_stream: BulkKeyStream::from_bytes(&bytes[21 + NONCE_LEN..])?,
+        })
+    }
+}
+
+/// One slice of the ciphertext. The offset says where it sits in the message and so
+/// which key-stream bytes encrypted it, and the MAC lets the server drop a damaged
+/// chunk and ask for it again instead of decrypting garbage. The MAC covers the
+/// message's header too, so a chunk can't be moved into another message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub sequence: u32,
+    pub offset: u64,
+    pub payload: Vec<u8>,
+    pub mac: [u8; 32],
+}
+
+impl Chunk {
+    pub fn new(
+        key: &[u8; 32],
+        header: &ChunkedHeader,
+        sequence: u32,
+        offset: u64,
+        payload: &[u8],
+    ) -> Chunk {
+        Chunk {
+            sequence,
+            offset,
+            payload: payload.to_vec(),
+            mac: chunk_mac(key, header, sequence, offset, payload)
+                .finalize()
+                .into_bytes()
+                .into(),
+        }
+    }
+
+    /// Cuts a whole ciphertext into chunks of at most `chunk_size` bytes
+    pub fn split(
+        key: &[u8; 32],
+        header: &ChunkedHeader,
+        ciphertext: &[u8],
+        chunk_size: usize,
+    ) -> Vec<Chunk> {
+        ciphertext
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(sequence, payload)| {
+                Chunk::new(
+                    key,
+                    header,
+                    sequence as u32,
+                    (sequence * chunk_size) as u64,
+                    payload,
+                )
+            })
+            .collect()
+    }
+
+    pub fn verify(&self, key: &[u8; 32], header: &ChunkedHeader) -> bool {
+        chunk_mac(key, header, self.sequence, self.offset, &self.payload)
+            .verify_slice(&self.mac)
+            .is_ok()
+    }
+
+    // [CHUNK] [sequence: u32] [offset: u64] [payload] [mac; 32]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![CHUNK];
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes.extend_from_slice(&self.mac);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Chunk> {
+        if bytes.len() < 45 || bytes[0] != CHUNK {
+            return None;
+        }
+        let (payload, mac) = bytes[13..].split_at(bytes.len() - 45);
+
+        Some(Chunk {
+            sequence: u32::from_le_bytes(bytes[1..5].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[5..13].try_into().unwrap()),
+            payload: payload.to_vec(),
+            mac: mac.try_into().unwrap(),
+        })
+    }
+}
+
+fn header_mac(key: &[u8; 32], fields: &[u8]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(b"chunked header");
+    mac.update(fields);
+    mac
+}
+
+fn chunk_mac(
+    key: &[u8; 32],
+    header: &ChunkedHeader,
+    sequence: u32,
+    offset: u64,
+    payload: &[u8],
+) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(b"chunk");
+    mac.update(&header.fields());
+    mac.update(&sequence.to_le_bytes());
+    mac.update(&offset.to_le_bytes());
+    mac.update(payload);
+    mac
+}
+
+// [MISSING_CHUNKS] [count: u32] [sequence: u32; count]
+pub fn missing_to_bytes(missing: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![MISSING_CHUNKS];
+    bytes.extend_from_slice(&(missing.len() as u32).to_le_bytes());
+    missing
+        .iter()
+        .for_each(|sequence| bytes.extend_from_slice(&sequence.to_le_bytes()));
+    bytes
+}
+
+pub fn missing_from_bytes(bytes: &[u8]) -> Option<Vec<u32>> {
+    if bytes.len() < 5 || bytes[0] != MISSING_CHUNKS {
+        return None;
+    }
+    let count = u32::from_le_bytes(bytes[1..5].try_into().unwrap()) as usize;
+
+    let sequences = &bytes[5..];
+    if sequences.len() != count * 4 {
+        return None;
+    }
+    Some(
+        sequences
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkRejection {
+    BadMac,
+    /// The sequence number or the byte range lies outside the announced message
+    OutOfRange,
+}
+
+/// Puts the ciphertext back together on the receiving side, in whatever order
+/// the chunks come in. The buffer only grows as far as the chunks that check out
+pub struct Reassembly {
+    header: ChunkedHeader,
+    ciphertext: Vec<u8>,
+    received: HashSet<u32>,
+    received_bytes: u64,
+}
+
+impl Reassembly {
+    pub fn new(header: ChunkedHeader) -> Reassembly {
+        Reassembly {
+            header,
+            ciphertext: Vec::new(),
+            received: HashSet::new(),
+            received_bytes: 0,
+        }
+    }
+
+    pub fn header(&self) -> &ChunkedHeader {
+        &self.header
+    }
+
+    pub fn insert(&mut self, key: &[u8; 32], chunk: &Chunk) -> Result<(), ChunkRejection> {
+        if !chunk.verify(key, &self.header) {
+            return Err(ChunkRejection::BadMac);
+        }
+        let end = chunk.offset.checked_add(chunk.payload.len() as u64);
+        if chunk.sequence >= self.header.chunk_count
+            || end.is_none_or(|end| end > self.header.total_len)
+        {
+            return Err(ChunkRejection::OutOfRange);
+        }
+
+        // a resent chunk that had arrived after all
+        if !self.received.insert(chunk.sequence) {
+            return Ok(());
+        }
+        let (offset, end) = (chunk.offset as usize, end.unwrap() as usize);
+        if self.ciphertext.len() < end {
+            self.ciphertext.resize(end, 0);
+        }
+        self.ciphertext[offset..end].copy_from_slice(&chunk.payload);
+        self.received_bytes += chunk.payload.len() as u64;
+        Ok(())
+    }
+
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.header.chunk_count)
+            .filter(|sequence| !self.received.contains(sequence))
+            .collect()
+    }
+
+    /// The whole ciphertext, once every chunk is in and they cover the message
+    pub fn ciphertext(&self) -> Option<&[u8]> {
+        (self.missing().is_empty()
+            && self.received_bytes == self.header.total_len
+            && self.ciphertext.len() as u64 == self.header.total_len)
+            .then_some(self.ciphertext.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [9; 32];
+    const NONCE: Nonce = [5; NONCE_LEN];
+
+    fn ciphertext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn test_frames_round_trip() {
+        let header = ChunkedHeader::new(
+            7,
+            1000,
+            64,
+            NONCE,
+            BulkKeyStream::Parallel { trajectories: 3 },
+        );
+        assert_eq!(header.chunk_count, 16);
+        assert_eq!(
+            ChunkedHeader::from_bytes(&header.to_bytes(&KEY), &KEY),
+            Some(header)
+        );
+
+        for chunk in Chunk::split(&KEY, &header, &ciphertext(1000), 64) {
+            assert_eq!(Chunk::from_bytes(&chunk.to_bytes()), Some(chunk));
+        }
+
+        let missing = vec![3, 1, 4];
+        assert_eq!(
+            missing_from_bytes(&missing_to_bytes(&missing)),
+            Some(missing)
+        );
+        assert_eq!(missing_from_bytes(&missing_to_bytes(&[])), Some(vec![]));
+    }
+
+    #[test]
+    fn test_malformed_frames_are_rejected() {
+        assert_eq!(Chunk::from_bytes(&[CHUNK; 44]), None);
+        assert_eq!(Chunk::from_bytes(&[CHUNKED_MESSAGE; 60]), None);
+        assert_eq!(
+            ChunkedHeader::from_bytes(&[CHUNKED_MESSAGE; 12], &KEY),
+            None
+        );
+
+        let mut too_many_chunks = ChunkedHeader::new(0, 10, 1, NONCE, BulkKeyStream::Lattice);
+        too_many_chunks.chunk_count = 11;
+        assert_eq!(
+            ChunkedHeader::from_bytes(&too_many_chunks.to_bytes(&KEY), &KEY),
+            None
+        );
+        let too_long = ChunkedHeader::new(
+            0,
+            MAX_MESSAGE_LEN as usize + 1,
+            1 << 20,
+            NONCE,
+            BulkKeyStream::Lattice,
+        );
+        assert_eq!(
+            ChunkedHeader::from_bytes(&too_long.to_bytes(&KEY), &KEY),
+            None
+        );
+        assert_eq!(
+            missing_from_bytes(&[MISSING_CHUNKS, 2, 0, 0, 0, 1, 0, 0, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_reassembles_out_of_order_and_after_loss() {
+        let ciphertext = ciphertext(1000);
+        let header = ChunkedHeader::new(0, ciphertext.len(), 64, NONCE, BulkKeyStream::Lattice);
+        let chunks = Chunk::split(&KEY, &header, &ciphertext, 64);
+        let mut reassembly = Reassembly::new(header);
+
+        // every other chunk goes missing on the first pass
+        for chunk in chunks.iter().rev().step_by(2) {
+            reassembly.insert(&KEY, chunk).unwrap();
+        }
+        assert_eq!(reassembly.missing(), vec![0, 2, 4, 6, 8, 10, 12, 14]);
+        assert_eq!(reassembly.ciphertext(), None);
+
+        for &sequence in &reassembly.missing() {
+            reassembly.insert(&KEY, &chunks[sequence as usize]).unwrap();
+        }
+        reassembly.insert(&KEY, &chunks[1]).unwrap();
+        assert!(reassembly.missing().is_empty());
+        assert_eq!(reassembly.ciphertext(), Some(ciphertext.as_slice()));
+    }
+
+    #[test]
+    fn test_tampered_chunks_are_rejected() {
+        let ciphertext = ciphertext(100);
+        let header = ChunkedHeader::new(0, ciphertext.len(), 64, NONCE, BulkKeyStream::Lattice);
+        let mut reassembly = Reassembly::new(header);
+        let mut chunks = Chunk::split(&KEY, &header, &ciphertext, 64);
+
+        chunks[0].payload[3] ^= 1;
+        assert_eq!(
+            reassembly.insert(&KEY, &chunks[0]),
+            Err(ChunkRejection::BadMac)
+        );
+        assert_eq!(
+            reassembly.insert(&[8; 32], &chunks[1]),
+            Err(ChunkRejection::BadMac)
+        );
+
+        let beyond = Chunk::new(&KEY, &header, 1, 90, &[0; 20]);
+        assert_eq!(
+            reassembly.insert(&KEY, &beyond),
+            Err(ChunkRejection::OutOfRange)
+        );
+        let extra = Chunk::new(&KEY, &header, 2, 0, &[0; 10]);
+        assert_eq!(
+            reassembly.insert(&KEY, &extra),
+            Err(ChunkRejection::OutOfRange)
+        );
+
+        assert_eq!(reassembly.missing(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_forged_headers_are_refused_before_allocating() {
+        let header = ChunkedHeader::new(0, 1000, 64, NONCE, BulkKeyStream::Lattice);
+        let mut bytes = header.to_bytes(&KEY);
+        assert_eq!(ChunkedHeader::from_bytes(&bytes, &[8; 32]), None);
+
+        // claims the largest message there is, under a MAC that no longer fits
+        bytes[9..17].copy_from_slice(&MAX_MESSAGE_LEN.to_le_bytes());
+        assert_eq!(ChunkedHeader::from_bytes(&bytes, &KEY), None);
+
+        let mut reassembly = Reassembly::new(ChunkedHeader::new(
+            0,
+            MAX_MESSAGE_LEN as usize,
+            1 << 20,
+            NONCE,
+            BulkKeyStream::Lattice,
+        ));
+        assert!(reassembly.ciphertext.capacity() < 1 << 20);
+        let header = *reassembly.header();
+        reassembly
+            .insert(&KEY, &Chunk::new(&KEY, &header, 1, 1 << 20, &[1; 10]))
+            .unwrap();
+        assert_eq!(reassembly.ciphertext.len(), (1 << 20) + 10);
+    }
+
+    #[test]
+    fn test_chunks_from_another_message_are_rejected() {
+        let ciphertext = ciphertext(100);
+        let first = ChunkedHeader::new(0, ciphertext.len(), 64, NONCE, BulkKeyStream::Lattice);
+        let spliced = Chunk::split(&KEY, &first, &ciphertext, 64);
+
+        for second in [
+            ChunkedHeader::new(0, ciphertext.len(), 64, [6; NONCE_LEN], first.key_stream),
+            ChunkedHeader::new(1, ciphertext.len(), 64, NONCE, first.key_stream),
+        ] {
+            let mut reassembly = Reassembly::new(second);
+            for chunk in &spliced {
+                assert_eq!(reassembly.insert(&KEY, chunk), Err(ChunkRejection::BadMac));
+            }
+        }
+
+        let other_length = ChunkedHeader::new(0, 90, 64, NONCE, first.key_stream);
+        let mut reassembly = Reassembly::new(other_length);
+        assert_eq!(
+            reassembly.insert(&KEY, &spliced[0]),
+            Err(ChunkRejection::BadMac)
+        );
+        assert_eq!(reassembly.missing(), vec![0, 1]);
+    }
+}
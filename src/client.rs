@@ -1,32 +1,51 @@
-use std::io::{self, Write};
-
-use tungstenite::{connect, Message};
+use std::{
+    fs,
+    io::{self, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use tungstenite::{connect, stream::MaybeTlsStream, Message, WebSocket};
 use url::Url;
 
 use rand::rngs::OsRng;
-use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use x25519_dalek::{PublicKey, StaticSecret};
 
 use base64::prelude::*;
-use strange_cipher::common;
+use strange_cipher::{
+    attractor::{self, AttractorKind},
+    chunk::{self, Chunk, ChunkedHeader},
+    common,
+    envelope::{ContentType, Encoding, Envelope},
+    handshake::{self, HandshakeMode},
+    masking,
+    nonce::{self, Nonce},
+    pipeline::{self, DeliveryError, InFlight, Receipt},
+    rekey::{RekeyPolicy, RekeySchedule, SessionKeys},
+    scheme::SyncScheme,
+    stream::BulkKeyStream,
+    sync::{self, DriveBatch, SyncConfig},
+    ticket::{self, Resumption, StoredTicket},
+};
 
 enum ClientState {
     Unverified,
-    Waiting {
-        rho: f64,
-        sigma: f64,
+    Waiting,
+    RequestingSync,
+    Syncing,
+    Encrypting {
+        nonce: Nonce,
     },
-    Syncing {
-        rho: f64,
-        sigma: f64,
+    Masking {
+        nonce: Nonce,
     },
-    Encrypting {
-        rho: f64,
-        sigma: f64,
+    BulkEncrypting {
+        key_stream: BulkKeyStream,
+        nonce: Nonce,
     },
     Encrypted {
-        rho: f64,
-        sigma: f64,
-        ciphertext: String,
+        envelope: Envelope,
     },
 }
 
@@ -43,11 +62,58 @@ fn encrypt(message: &str, key_stream: &[u8]) -> Vec<u8> {
     ciphertext
 }
 
-pub fn main() {
-    env_logger::init();
+#[derive(Clone, Copy, PartialEq)]
+enum CipherMode {
+    /// XOR the message with the key stream
+    Xor,
+    /// Carry the message on the drive signal, see `masking`, sealed in an envelope
+    Masking,
+    /// XOR the message with a coupled map lattice key stream as long as itself
+    Lattice,
+    /// XOR the message with the key streams of several trajectories, see `parallel`
+    Parallel,
+}
+
+struct Options {
+    handshake: HandshakeMode,
+    server_key: Option<String>,
+    batch_size: usize,
+    attractor: AttractorKind,
+    scheme: SyncScheme,
+    cipher: CipherMode,
+    envelope: Encoding,
+    trajectories: u8,
+    chunk_size: Option<usize>,
+    /// Leaves a chunk out of the first pass, to exercise the resend
+    drop_chunk: Option<u32>,
+    /// Sends the previous message's envelope again in place of the next one, to
+    /// exercise the server's replay protection
+    replay: bool,
+    /// Flips a bit of every ciphertext after sealing, to exercise the server's MAC check
+    tamper: bool,
+    /// Where the resumption ticket is kept between runs
+    ticket: Option<PathBuf>,
+    rekey: RekeyPolicy,
+    /// How many messages may wait on a receipt at once. Past one, they are all keyed
+    /// from the same sync
+    pipeline: usize,
+}
 
-    let (mut socket, response) =
-        connect(Url::parse("ws://localhost:3012/socket").unwrap()).expect("Can't connect");
+fn connect_to_server() -> WebSocket<MaybeTlsStream<TcpStream>> {
+    let (socket, response) = match connect(Url::parse("ws://localhost:3012/socket").unwrap()) {
+        Ok(connection) => connection,
+        // a full server says so in the body of its refusal
+        Err(tungstenite::Error::Http(response)) => {
+            let reason = response.body().as_deref().map(String::from_utf8_lossy);
+            eprintln!(
+                "The server turned us away ({}): {}",
+                response.status(),
+                reason.as_deref().unwrap_or("no reason given")
+            );
+            std::process::exit(1);
+        }
+        Err(e) => panic!("Can't connect: {}", e),
+    };
 
     println!("Connected to the server");
     println!("Response HTTP code: {}", response.status());
@@ -56,13 +122,317 @@ pub fn main() {
         println!("* {}", header);
     }
 
-    let seed = (-10.0, -7.0, 35.0);
-    let beta = 8.0 / 3.0;
-    let h = 0.01;
+    socket
+}
+
+// Whatever the server sent while we waited on the user: pings, or a close if it
+// ended the session. Only called with nothing in flight, so no receipt is swallowed.
+// Only a plain socket can be polled, over TLS a close shows up with the next read
+fn closed_while_idle(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Option<String> {
+    let MaybeTlsStream::Plain(stream) = socket.get_mut() else {
+        return None;
+    };
+    if let Err(e) = stream.set_nonblocking(true) {
+        return Some(format!("Lost the connection to the server: {}", e));
+    }
+
+    let closed = loop {
+        match common::read_non_blocking(socket) {
+            Ok(Some(Message::Close(frame))) => {
+                let reason = frame.map(|frame| frame.reason.to_string());
+                break Some(format!(
+                    "The server closed the connection: {}",
+                    reason.as_deref().unwrap_or("no reason given")
+                ));
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => break None,
+            Err(e) => break Some(format!("Lost the connection to the server: {}", e)),
+        }
+    };
+
+    if let MaybeTlsStream::Plain(stream) = socket.get_mut() {
+        if let Err(e) = stream.set_nonblocking(false) {
+            return Some(format!("Lost the connection to the server: {}", e));
+        }
+    }
+    closed
+}
+
+// Reads receipts until no more than `keep` messages are waiting on one, and adds
+// them to the session's tally
+fn settle_receipts<S>(
+    socket: &mut WebSocket<S>,
+    in_flight: &mut InFlight,
+    keep: usize,
+    deliveries: &mut Vec<Receipt>,
+) -> Result<(), DeliveryError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    for receipt in pipeline::await_receipts(socket, in_flight, keep)? {
+        match receipt {
+            Receipt::Delivered(id) => println!("Delivered message {}", id),
+            Receipt::Refused { id, code } => println!("Message {} was refused: {}", id, code),
+        }
+        deliveries.push(receipt);
+    }
+    Ok(())
+}
+
+// Counts a message as waiting on its receipt, and waits on the oldest ones while
+// the pipeline is full
+fn track<S>(
+    socket: &mut WebSocket<S>,
+    in_flight: &mut InFlight,
+    id: u64,
+    depth: usize,
+    deliveries: &mut Vec<Receipt>,
+) -> Result<(), DeliveryError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    in_flight.sent(id);
+    if in_flight.is_full() {
+        settle_receipts(socket, in_flight, depth - 1, deliveries)?;
+    }
+    Ok(())
+}
+
+// Sends the chunks, then resends whatever the server reports missing until it has
+// them all
+fn send_chunked<S>(
+    socket: &mut WebSocket<S>,
+    key: &[u8; 32],
+    header: &ChunkedHeader,
+    chunks: &[Chunk],
+    drop_chunk: Option<u32>,
+    max_attempts: usize,
+) -> Result<(), String>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let lost = |e: tungstenite::Error| format!("lost the connection to the server: {}", e);
+    socket
+        .send(Message::Binary(header.to_bytes(key)))
+        .map_err(lost)?;
+    println!(
+        "Sent: Chunked Message ({} bytes in {} chunks)",
+        header.total_len, header.chunk_count
+    );
+
+    let mut pending: Vec<u32> = (0..header.chunk_count).collect();
+    for attempt in 0..max_attempts {
+        for &sequence in &pending {
+            if attempt == 0 && drop_chunk == Some(sequence) {
+                println!("Dropping chunk {}", sequence);
+                continue;
+            }
+            socket
+                .send(Message::Binary(chunks[sequence as usize].to_bytes()))
+                .map_err(lost)?;
+        }
+        socket
+            .send(Message::Binary(vec![chunk::CHUNKS_SENT]))
+            .map_err(lost)?;
+        println!("Sent: Chunks Sent");
+
+        let missing = match common::read_data(socket) {
+            Ok(Message::Binary(v)) => chunk::missing_from_bytes(&v),
+            _ => None,
+        };
+        match missing {
+            Some(missing) if missing.is_empty() => return Ok(()),
+            Some(missing) => {
+                println!("The server is missing chunks {:?}", missing);
+                pending = missing
+                    .into_iter()
+                    .filter(|&sequence| sequence < header.chunk_count)
+                    .collect();
+            }
+            None => return Err("the server stopped responding during the transfer".into()),
+        }
+    }
+
+    Err(format!(
+        "the server was still missing chunks after {} attempts",
+        max_attempts
+    ))
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        handshake: HandshakeMode::NoiseXX,
+        server_key: None,
+        batch_size: 256,
+        attractor: AttractorKind::Lorenz,
+        scheme: SyncScheme::Replacement,
+        cipher: CipherMode::Xor,
+        envelope: Encoding::Binary,
+        trajectories: 4,
+        chunk_size: None,
+        drop_chunk: None,
+        replay: false,
+        tamper: false,
+        ticket: None,
+        rekey: RekeyPolicy::default(),
+        pipeline: 1,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--handshake" => {
+                let name = args.next().expect("--handshake needs a value");
+                options.handshake = HandshakeMode::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown handshake: {}", name));
+            }
+            "--server-key" => options.server_key = args.next(),
+            "--attractor" => {
+                let name = args.next().expect("--attractor needs a value");
+                options.attractor = AttractorKind::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown attractor: {}", name));
+            }
+            "--sync-scheme" => {
+                let name = args.next().expect("--sync-scheme needs a value");
+                options.scheme = SyncScheme::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown sync scheme: {}", name));
+            }
+            "--cipher" => {
+                options.cipher = match args.next().as_deref() {
+                    Some("xor") => CipherMode::Xor,
+                    Some("masking") => CipherMode::Masking,
+                    Some("lattice") => CipherMode::Lattice,
+                    Some("parallel") => CipherMode::Parallel,
+                    other => panic!("Unknown cipher: {:?}", other),
+                }
+            }
+            "--envelope" => {
+                let name = args.next().expect("--envelope needs a value");
+                options.envelope = Encoding::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown envelope encoding: {}", name));
+            }
+            "--trajectories" => {
+                options.trajectories = args
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .filter(|&count| count > 0)
+                    .expect("--trajectories needs a number from 1 to 255");
+            }
+            "--chunk-size" => {
+                options.chunk_size = args
+                    .next()
+                    .and_then(|size| size.parse().ok())
+                    .filter(|&size| size > 0)
+                    .map(Some)
+                    .expect("--chunk-size needs a positive number");
+            }
+            "--drop-chunk" => {
+                options.drop_chunk = args.next().and_then(|sequence| sequence.parse().ok());
+            }
+            "--replay" => options.replay = true,
+            "--tamper" => options.tamper = true,
+            "--ticket" => options.ticket = args.next().map(PathBuf::from),
+            "--pipeline" => {
+                options.pipeline = args
+                    .next()
+                    .and_then(|depth| depth.parse().ok())
+                    .filter(|&depth| depth > 0)
+                    .expect("--pipeline needs a positive number");
+            }
+            "--rekey-messages" => {
+                options.rekey.max_messages = args
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .filter(|&count| count > 0)
+                    .map(Some)
+                    .expect("--rekey-messages needs a positive number");
+            }
+            "--rekey-bytes" => {
+                options.rekey.max_bytes = args
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .filter(|&count| count > 0)
+                    .map(Some)
+                    .expect("--rekey-bytes needs a positive number");
+            }
+            "--rekey-secs" => {
+                options.rekey.max_age = args
+                    .next()
+                    .and_then(|secs| secs.parse().ok())
+                    .map(|secs| Some(Duration::from_secs(secs)))
+                    .expect("--rekey-secs needs a number");
+            }
+            "--batch-size" => {
+                options.batch_size = args
+                    .next()
+                    .and_then(|size| size.parse().ok())
+                    .filter(|&size| size > 0)
+                    .expect("--batch-size needs a positive number");
+            }
+            _ => panic!("Unknown argument: {}", arg),
+        }
+    }
+
+    if !options.attractor.syncs_with(&options.scheme) {
+        panic!(
+            "The {} attractor can't be synced with the {} scheme",
+            options.attractor, options.scheme
+        );
+    }
+    if options.pipeline > 1 && options.cipher != CipherMode::Xor {
+        panic!("Only the xor cipher's envelopes can be pipelined");
+    }
+    if options.chunk_size.is_some()
+        && !matches!(options.cipher, CipherMode::Lattice | CipherMode::Parallel)
+    {
+        panic!("Only the lattice and parallel ciphers can be sent in chunks");
+    }
+    // a byte on the drive would knock a map out of its domain
+    if options.cipher == CipherMode::Masking && options.attractor.is_discrete() {
+        panic!(
+            "Masking needs a continuous attractor, not {}",
+            options.attractor
+        );
+    }
+
+    options
+}
+
+pub fn main() {
+    env_logger::init();
+
+    let options = parse_args();
+    let static_key = StaticSecret::random_from_rng(OsRng);
+    let server_key = options.server_key.as_ref().map(|key| {
+        let bytes: [u8; 32] = BASE64_STANDARD
+            .decode(key)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .expect("The server key must be 32 base64 encoded bytes");
+        PublicKey::from(bytes)
+    });
+
+    let mut socket = connect_to_server();
+
     let mut stream_state = ClientState::Unverified;
     let mut key_stream = Vec::new();
-
-    let mut state = (0.0, 0.0, 0.0);
+    let mut sent_states = Vec::new();
+    let sync_config = SyncConfig::default();
+    let mut sync_key = [0; 32];
+    let mut attractor = options.attractor.build(&sync_key);
+    let mut sync_attempts = 0;
+    let mut message_counter = 0;
+    let mut last_envelope: Option<Envelope> = None;
+    let mut failure = None;
+    let mut keys = SessionKeys::new(&sync_key, &sync_key);
+    let mut rekey_schedule = RekeySchedule::new(options.rekey);
+    let mut in_flight = InFlight::new(options.pipeline);
+    let mut deliveries = Vec::new();
+    // the state the last sync agreed on, while it can key further pipelined messages
+    let mut last_sync: Option<Vec<f64>> = None;
+
+    let mut state = Vec::new();
     let mut input = String::new();
 
     loop {
@@ -70,37 +440,62 @@ pub fn main() {
             ClientState::Unverified => {
                 println!("Starting Key exchange");
 
-                let client_secret_key = EphemeralSecret::random_from_rng(OsRng);
-                let client_public_key = PublicKey::from(&client_secret_key);
-                let shared_secret: SharedSecret;
-
-                socket
-                    .send(Message::Binary(client_public_key.to_bytes().to_vec()))
-                    .expect("Could not send public key");
-
-                match socket.read() {
-                    Ok(Message::Binary(server_public_key_bytes)) => {
-                        let mut byte_array: [u8; 32] = [0; 32];
-                        byte_array[..32].copy_from_slice(server_public_key_bytes.as_slice());
-                        let server_public_key = PublicKey::from(byte_array);
-
-                        shared_secret = client_secret_key.diffie_hellman(&server_public_key);
+                let stored = options
+                    .ticket
+                    .as_ref()
+                    .and_then(|path| fs::read(path).ok())
+                    .and_then(|bytes| StoredTicket::from_bytes(&bytes));
+                let resumed = match stored {
+                    Some(stored) => match handshake::resume(&mut socket, &stored) {
+                        Ok(outcome) => Some(outcome),
+                        Err(e) => {
+                            // the server hangs up on a ticket it can't use
+                            println!("Resumption failed: {}. Starting a full handshake", e);
+                            socket = connect_to_server();
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                let outcome = match resumed {
+                    Some(outcome) => {
+                        println!("Resumed the previous session");
+                        outcome
+                    }
+                    None => {
+                        handshake::initiate(&mut socket, options.handshake, &static_key, server_key)
+                            .expect("Key exchange failed")
+                    }
+                };
+                println!("Key confirmed");
+
+                sync_key = outcome.chaining_key;
+                let parameter_key = outcome.parameter_key();
+                attractor = options.attractor.build(&parameter_key);
+                println!("Using the {} attractor", options.attractor);
+
+                if let Some(path) = &options.ticket {
+                    common::send_request(&mut socket, "Ticket Request", ticket::TICKET_REQUEST);
+                    match common::read_data(&mut socket) {
+                        Ok(Message::Binary(v)) if v.first() == Some(&ticket::NEW_TICKET) => {
+                            let stored = StoredTicket {
+                                ticket: v[1..].to_vec(),
+                                resumption: Resumption::of(&parameter_key, &sync_key),
+                            };
+                            fs::write(path, stored.to_bytes()).expect("Couldn't save the ticket");
+                            println!("Received: Resumption Ticket");
+                        }
+                        other => panic!("Expected a resumption ticket, got {:?}", other),
                     }
-                    _ => panic!("Recieved Invalid Key"),
                 }
 
-                let result = shared_secret.to_bytes()[10];
-                let rho = common::lin_interp(result as f64, 0.0, 24.0, 255.0, 57.0);
-                let sigma = common::interpolate_sigma(rho);
-
-                println!("rho = {}", rho);
-                println!("sigma = {}", sigma);
+                state = attractor.step(&attractor.initial_state());
+                keys = SessionKeys::new(&parameter_key, &sync_key);
+                rekey_schedule.reset();
 
-                state = common::lorenz_attractor(seed.0, None, seed.1, seed.2, sigma, rho, beta, h);
-
-                stream_state = ClientState::Waiting { rho, sigma };
+                stream_state = ClientState::Waiting;
             }
-            ClientState::Waiting { rho, sigma } => {
+            ClientState::Waiting => {
                 match socket.get_mut() {
                     tungstenite::stream::MaybeTlsStream::Plain(stream) => {
                         stream.set_nonblocking(false)
@@ -115,89 +510,399 @@ pub fn main() {
                 io::stdin().read_line(&mut input).unwrap();
                 let input = input.trim().to_string();
 
-                if input == "" {
+                if in_flight.is_empty() {
+                    if let Some(reason) = closed_while_idle(&mut socket) {
+                        failure = Some(reason);
+                        break;
+                    }
+                }
+
+                // receipts come back on the same socket, so whatever else needs the
+                // server's answer waits until the whole pipeline is settled
+                if input.is_empty() || rekey_schedule.due() || last_sync.is_none() {
+                    if let Err(reason) =
+                        settle_receipts(&mut socket, &mut in_flight, 0, &mut deliveries)
+                    {
+                        failure = Some(format!("Delivery failed: {}", reason));
+                        break;
+                    }
+                }
+
+                if input.is_empty() {
                     common::send_request(&mut socket, "Cancel Request", 0);
                     break;
                 }
 
-                common::send_request(&mut socket, "Sync Request", 1);
-                common::receive_msg(&mut socket);
-                stream_state = ClientState::Syncing { rho, sigma };
+                if rekey_schedule.due() {
+                    socket
+                        .send(Message::Binary(keys.request()))
+                        .expect("Unable to send request: Rekey Request");
+                    println!("Sent: Rekey Request (epoch {})", keys.epoch + 1);
+
+                    // nothing else is in flight, so both sides switch between the
+                    // same two messages
+                    keys.ratchet();
+                    match common::read_data(&mut socket) {
+                        Ok(Message::Binary(v)) if keys.verify_acknowledgement(&v) => {
+                            println!("Rekeyed to epoch {}", keys.epoch);
+                        }
+                        _ => {
+                            failure = Some("Rekey failed: the server didn't switch keys".into());
+                            break;
+                        }
+                    }
+                    sync_key = keys.sync_key;
+                    attractor = options.attractor.build(&keys.parameter_key);
+                    state = keys.initial_state(attractor.as_ref());
+                    last_sync = None;
+                    rekey_schedule.reset();
+                }
+                rekey_schedule.record(input.len());
+
+                if let Some(synced_state) = &last_sync {
+                    // a fresh nonce keeps its key stream apart from the others
+                    let nonce = nonce::generate();
+                    key_stream.clear();
+                    state =
+                        nonce::initial_state(attractor.as_ref(), &sync_key, synced_state, &nonce);
+                    stream_state = ClientState::Encrypting { nonce };
+                    continue;
+                }
+
+                sync_attempts = 0;
+                stream_state = ClientState::RequestingSync;
             }
-            ClientState::Syncing { rho, sigma } => {
+            ClientState::RequestingSync => {
                 match socket.get_mut() {
                     tungstenite::stream::MaybeTlsStream::Plain(stream) => {
-                        stream.set_nonblocking(true)
+                        stream.set_nonblocking(false)
                     }
                     _ => unimplemented!(),
                 }
-                .expect("Could not make socket non-blocking");
-
-                state =
-                    common::lorenz_attractor(state.0, None, state.1, state.2, sigma, rho, beta, h);
-                socket
-                    .send(Message::Binary(state.0.to_ne_bytes().to_vec()))
-                    .expect("Could not send x coordinate");
-                socket
-                    .send(Message::Binary(state.1.to_ne_bytes().to_vec()))
-                    .expect("Could not send y coordinate");
-                socket
-                    .send(Message::Binary(state.2.to_ne_bytes().to_vec()))
-                    .expect("Could not send z coordinate");
-
-                match common::read_non_blocking(&mut socket) {
-                    Some(Message::Binary(v)) if v.as_slice() == [2] => {
-                        println!("Server finished syncing. Encrypting now");
-                        stream_state = ClientState::Encrypting { rho, sigma };
+                .expect("Could not make socket blocking");
+
+                let mut request = vec![1, options.attractor as u8];
+                request.extend_from_slice(&options.scheme.to_bytes());
+                // the server may have ended an idle session, or be shutting down
+                let approval = match socket.send(Message::Binary(request)) {
+                    Ok(()) => common::read_data(&mut socket),
+                    Err(e) => Err(e),
+                };
+                println!(
+                    "Sent: Sync Request ({}, {})",
+                    options.attractor, options.scheme
+                );
+                match approval {
+                    Ok(Message::Close(frame)) => {
+                        let reason = frame.map(|frame| frame.reason.to_string());
+                        failure = Some(format!(
+                            "The server closed the connection: {}",
+                            reason.as_deref().unwrap_or("no reason given")
+                        ));
+                        break;
+                    }
+                    Ok(msg) => println!("Recieved: {}", msg),
+                    Err(e) => {
+                        failure = Some(format!("Lost the connection to the server: {}", e));
+                        break;
                     }
-                    _ => (),
                 }
+                sent_states.clear();
+                stream_state = ClientState::Syncing;
             }
+            ClientState::Syncing => {
+                match socket.get_mut() {
+                    tungstenite::stream::MaybeTlsStream::Plain(stream) => stream
+                        .set_nonblocking(false)
+                        .and_then(|_| stream.set_read_timeout(Some(sync_config.timeout * 2))),
+                    _ => unimplemented!(),
+                }
+                .expect("Could not make socket blocking");
 
-            ClientState::Encrypting { rho, sigma } => {
-                if key_stream.len() == 16 {
-                    let ciphertext = BASE64_STANDARD.encode(encrypt(input.as_str(), &key_stream));
-                    stream_state = ClientState::Encrypted {
-                        rho,
-                        sigma,
-                        ciphertext,
-                    };
-                    continue;
+                let sent = sent_states.len();
+                for _ in 0..options.batch_size {
+                    state = attractor.step(&state);
+                    sent_states.push(state.clone());
+                }
+                let batch = DriveBatch::from_states(sent as u64 + 1, &sent_states[sent..]);
+                socket
+                    .send(Message::Binary(batch.to_bytes()))
+                    .expect("Could not send drive batch");
+
+                match common::read_data(&mut socket) {
+                    Ok(Message::Binary(v)) if v.len() == 41 && v[0] == 2 => {
+                        println!("Server finished syncing");
+
+                        // we ran ahead of the server, so go back to the step it synced
+                        // on and snap to the same grid it did. A step we never sent
+                        // can't match, and counts as a failed sync
+                        let step = u64::from_le_bytes(v[1..9].try_into().unwrap());
+                        let candidate = step
+                            .checked_sub(1)
+                            .and_then(|index| sent_states.get(index as usize))
+                            .map(|synced| {
+                                sync::quantize_state(&attractor.step(synced), sync_config.quantum)
+                            })
+                            .filter(|candidate| {
+                                sync::verify_commitment(&sync_key, step, candidate, &v[9..41])
+                            });
+
+                        if let Some(candidate) = candidate {
+                            println!("Synced state confirmed. Encrypting now");
+                            if options.pipeline > 1 {
+                                last_sync = Some(candidate.clone());
+                            }
+                            key_stream.clear();
+                            // the key streams start from the synced state mixed with a
+                            // fresh nonce, so no two messages ever share one
+                            let nonce = nonce::generate();
+                            state = nonce::initial_state(
+                                attractor.as_ref(),
+                                &sync_key,
+                                &candidate,
+                                &nonce,
+                            );
+                            stream_state = match options.cipher {
+                                CipherMode::Xor => ClientState::Encrypting { nonce },
+                                CipherMode::Masking => ClientState::Masking { nonce },
+                                CipherMode::Lattice => ClientState::BulkEncrypting {
+                                    key_stream: BulkKeyStream::Lattice,
+                                    nonce,
+                                },
+                                CipherMode::Parallel => ClientState::BulkEncrypting {
+                                    key_stream: BulkKeyStream::Parallel {
+                                        trajectories: options.trajectories,
+                                    },
+                                    nonce,
+                                },
+                            };
+                            continue;
+                        }
+                        println!("The server's synced state doesn't match ours");
+                    }
+                    Ok(Message::Binary(v)) if v.as_slice() == [4] => {
+                        println!("Received: Sync Failed");
+                    }
+                    Ok(Message::Binary(v)) if v.as_slice() == [6] => continue,
+                    _ => {
+                        failure =
+                            Some("Sync failed: the server stopped responding while syncing".into());
+                        break;
+                    }
                 }
 
-                state =
-                    common::lorenz_attractor(state.0, None, state.1, state.2, sigma, rho, beta, h);
+                sync_attempts += 1;
+                if sync_attempts >= sync_config.max_attempts {
+                    failure = Some(format!(
+                        "Sync failed: the server could not sync after {} attempts",
+                        sync_attempts
+                    ));
+                    common::send_request(&mut socket, "Cancel Request", 0);
+                    break;
+                }
 
-                let bytes = state.1.to_ne_bytes();
-                bytes.iter().for_each(|e| key_stream.push(*e));
+                // try again from a fresh trajectory
+                state = sync::perturb(&state, 1.0);
+                stream_state = ClientState::RequestingSync;
             }
 
-            ClientState::Encrypted {
-                rho,
-                sigma,
-                ref ciphertext,
-            } => {
-                println!("Finished encrypting with message = {}", ciphertext);
-                println!("Sending encrypted message");
+            ClientState::Encrypting { nonce } => {
+                if key_stream.len() >= 16 {
+                    key_stream.truncate(16);
+                    nonce::mask(&sync_key, &nonce, &mut key_stream);
+                    // the key is the first one after the nonce's starting point
+                    let envelope = Envelope::seal(
+                        &sync_key,
+                        nonce,
+                        message_counter,
+                        0,
+                        ContentType::Text,
+                        encrypt(input.as_str(), &key_stream),
+                    );
+                    message_counter += 1;
+                    stream_state = ClientState::Encrypted { envelope };
+                    continue;
+                }
 
-                common::send_request(&mut socket, "Encryption Completed", 3);
+                state = attractor.step(&state);
+                key_stream.extend(attractor::key_bytes(attractor.as_ref(), &state));
+            }
 
+            ClientState::Masking { nonce } => {
+                let signal = masking::mask(input.as_bytes(), attractor.as_ref(), &state);
+                // anyone who can try every parameter set could read the signal
+                // itself, so it goes out like a ciphertext
+                let mut masked_signal = DriveBatch {
+                    first_step: 1,
+                    x: signal,
+                }
+                .to_bytes();
+                nonce::mask(&sync_key, &nonce, &mut masked_signal);
+                let envelope = Envelope::seal(
+                    &sync_key,
+                    nonce,
+                    message_counter,
+                    0,
+                    ContentType::Text,
+                    masked_signal,
+                );
+                message_counter += 1;
+
+                let id = envelope.counter;
+                let mut request = vec![7, options.envelope as u8];
+                request.extend_from_slice(&id.to_le_bytes());
                 socket
-                    .send(Message::Text(ciphertext.to_string()))
-                    .expect("Could not send ciphertext");
+                    .send(Message::Binary(request))
+                    .expect("Unable to send request: Masked Message");
+                println!("Sent: Masked Message ({} envelope)", options.envelope);
+                let message = match options.envelope {
+                    Encoding::Json => Message::Text(envelope.to_json()),
+                    encoding => Message::Binary(encoding.encode(&envelope)),
+                };
+                socket.send(message).expect("Could not send masked signal");
+                println!("Sent masked message");
+
+                if let Err(reason) = track(
+                    &mut socket,
+                    &mut in_flight,
+                    id,
+                    options.pipeline,
+                    &mut deliveries,
+                ) {
+                    failure = Some(format!("Delivery failed: {}", reason));
+                    break;
+                }
+                stream_state = ClientState::Waiting;
+            }
 
-                let fst: &[u8] = &key_stream[0..8];
-                fst.iter().for_each(|&item| {
+            ClientState::BulkEncrypting { key_stream, nonce } => {
+                // seeded from the synced state and the nonce, which the server gets
+                let mut ciphertext = input.as_bytes().to_vec();
+                key_stream
+                    .build(attractor.as_ref(), &sync_key, &state)
+                    .apply(&mut ciphertext);
+                nonce::mask(&sync_key, &nonce, &mut ciphertext);
+                let id = message_counter;
+                message_counter += 1;
+
+                if let Some(chunk_size) = options.chunk_size {
+                    let header =
+                        ChunkedHeader::new(id, ciphertext.len(), chunk_size, nonce, key_stream);
+                    let chunks = Chunk::split(&sync_key, &header, &ciphertext, chunk_size);
+                    if let Err(reason) = send_chunked(
+                        &mut socket,
+                        &sync_key,
+                        &header,
+                        &chunks,
+                        options.drop_chunk,
+                        sync_config.max_attempts,
+                    ) {
+                        failure = Some(format!("Transfer failed: {}", reason));
+                        // the server may already be gone
+                        if socket.send(Message::Binary(vec![0])).is_ok() {
+                            println!("Sent: Cancel Request");
+                        }
+                        break;
+                    }
+                } else {
+                    let mut request = match key_stream {
+                        BulkKeyStream::Lattice => vec![8],
+                        BulkKeyStream::Parallel { .. } => vec![9],
+                    };
+                    request.extend_from_slice(&id.to_le_bytes());
+                    if let BulkKeyStream::Parallel { trajectories } = key_stream {
+                        request.push(trajectories);
+                    }
+                    request.extend_from_slice(&nonce);
                     socket
-                        .send(Message::Binary(vec![item]))
-                        .expect("Could not send byte")
-                });
+                        .send(Message::Binary(request))
+                        .expect("Unable to send request: Bulk Encrypted Message");
+                    println!("Sent: Bulk Encrypted Message ({:?})", key_stream);
+                    socket
+                        .send(Message::Text(BASE64_STANDARD.encode(ciphertext)))
+                        .expect("Could not send ciphertext");
+                }
+
+                if let Err(reason) = track(
+                    &mut socket,
+                    &mut in_flight,
+                    id,
+                    options.pipeline,
+                    &mut deliveries,
+                ) {
+                    failure = Some(format!("Delivery failed: {}", reason));
+                    break;
+                }
+                stream_state = ClientState::Waiting;
+            }
+
+            ClientState::Encrypted { ref envelope } => {
+                let envelope = match &last_envelope {
+                    Some(previous) if options.replay => {
+                        println!("Replaying message {}", previous.counter);
+                        previous
+                    }
+                    _ => envelope,
+                };
+                let tampered;
+                let envelope = if options.tamper {
+                    let mut copy = envelope.clone();
+                    copy.ciphertext[0] ^= 1;
+                    println!("Tampering with message {}", copy.counter);
+                    tampered = copy;
+                    &tampered
+                } else {
+                    envelope
+                };
+                println!(
+                    "Finished encrypting with message = {}",
+                    BASE64_STANDARD.encode(&envelope.ciphertext)
+                );
+                println!("Sending encrypted message");
 
-                stream_state = ClientState::Waiting { rho, sigma };
+                let mut request = vec![3, options.envelope as u8];
+                request.extend_from_slice(&envelope.counter.to_le_bytes());
+                socket
+                    .send(Message::Binary(request))
+                    .expect("Unable to send request: Encryption Completed");
+                println!("Sent: Encryption Completed ({} envelope)", options.envelope);
+
+                let message = match options.envelope {
+                    Encoding::Json => Message::Text(envelope.to_json()),
+                    encoding => Message::Binary(encoding.encode(envelope)),
+                };
+                socket.send(message).expect("Could not send ciphertext");
+
+                let id = envelope.counter;
+                last_envelope = Some(envelope.clone());
+                if let Err(reason) = track(
+                    &mut socket,
+                    &mut in_flight,
+                    id,
+                    options.pipeline,
+                    &mut deliveries,
+                ) {
+                    failure = Some(format!("Delivery failed: {}", reason));
+                    break;
+                }
+                stream_state = ClientState::Waiting;
             }
         }
     }
 
+    if !deliveries.is_empty() {
+        let delivered = deliveries
+            .iter()
+            .filter(|receipt| matches!(receipt, Receipt::Delivered(_)))
+            .count();
+        println!("Delivered {} of {} messages", delivered, deliveries.len());
+    }
+
+    if let Some(reason) = failure {
+        eprintln!("{}", reason);
+        std::process::exit(1);
+    }
+
     println!("Done. Bye bye");
 }
 
@@ -0,0 +1,718 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::{
+    attractor::{Attractor, AttractorKind},
+    parallel::ParallelKeyStream,
+    stream::{DecryptReader, EncryptWriter, KeyStream},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const MAGIC: &[u8; 4] = b"SCIF";
+pub const VERSION: u8 = 1;
+const HEADER_LEN: usize = 4 + 1 + 1 + 12 + 1 + 1 + 1 + 16 + 16;
+const TAG_LEN: usize = 32;
+/// The fewest coordinates an attractor needs to encrypt files with. The
+/// one-dimensional maps are kept to the live session ciphers
+pub const MIN_DIMENSION: usize = 2;
+const MASK_BLOCK_LEN: usize = 32;
+/// How far past the defaults a header's Argon2 costs may go. The costs are read
+/// before anything can check them, so a file could otherwise ask for any amount of
+/// memory and time
+const MAX_COST_FACTOR: u32 = 8;
+
+/// How the file key is derived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    /// Argon2id over a password, with its costs kept in the header
+    Password {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    /// HMAC-SHA256 over the contents of a key file, which should hold at least
+    /// 32 random bytes
+    KeyFile,
+}
+
+impl Kdf {
+    pub fn password() -> Kdf {
+        Kdf::Password {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+
+    fn within_limits(&self) -> bool {
+        match *self {
+            Kdf::Password {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                m_cost <= Params::DEFAULT_M_COST * MAX_COST_FACTOR
+                    && t_cost <= Params::DEFAULT_T_COST * MAX_COST_FACTOR
+                    && p_cost <= Params::DEFAULT_P_COST * MAX_COST_FACTOR
+            }
+            Kdf::KeyFile => true,
+        }
+    }
+}
+
+/// How each step of the flow is integrated. The maps ignore it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Integrator {
+    Euler = 0,
+}
+
+pub enum Credential<'a> {
+    Password(&'a [u8]),
+    KeyFile(&'a [u8]),
+}
+
+/// Everything needed to rebuild the key stream but the credential:
+///
+/// `[magic; 4] [version] [kdf] [m_cost, t_cost, p_cost: u32] [attractor] [integrator]
+/// [trajectories] [salt; 16] [nonce; 16] [header MAC; 32]`
+///
+/// followed by the ciphertext and an HMAC of the header and ciphertext
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub kdf: Kdf,
+    pub attractor: AttractorKind,
+    pub integrator: Integrator,
+    pub trajectories: u8,
+    pub salt: [u8; 16],
+    pub nonce: [u8; 16],
+}
+
+impl Header {
+    /// A header with a fresh salt and nonce
+    pub fn new(kdf: Kdf, attractor: AttractorKind, trajectories: u8) -> Header {
+        let mut salt = [0; 16];
+        let mut nonce = [0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        Header {
+            kdf,
+            attractor,
+            integrator: Integrator::Euler,
+            trajectories,
+            salt,
+            nonce,
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let (kdf, costs) = match self.kdf {
+            Kdf::Password {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => (0, [m_cost, t_cost, p_cost]),
+            Kdf::KeyFile => (1, [0; 3]),
+        };
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.push(kdf);
+        costs
+            .iter()
+            .for_each(|cost| bytes.extend_from_slice(&cost.to_le_bytes()));
+        bytes.push(self.attractor as u8);
+        bytes.push(self.integrator as u8);
+        bytes.push(self.trajectories);
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Result<Header, ContainerError> {
+        if &bytes[..4] != MAGIC {
+            return Err(ContainerError::NotAContainer);
+        }
+        if bytes[4] != VERSION {
+            return Err(ContainerError::UnsupportedVersion(bytes[4]));
+        }
+
+        let cost = |i: usize| u32::from_le_bytes(bytes[6 + 4 * i..10 + 4 * i].try_into().unwrap());
+        let kdf = match bytes[5] {
+            0 => Kdf::Password {
+                m_cost: cost(0),
+                t_cost: cost(1),
+                p_cost: cost(2),
+            },
+            1 => Kdf::KeyFile,
+            other => return Err(ContainerError::UnknownKdf(other)),
+        };
+        if !kdf.within_limits() {
+            return Err(ContainerError::CostsTooHigh);
+        }
+        let attractor = AttractorKind::from_byte(bytes[18])
+            .ok_or(ContainerError::UnknownAttractor(bytes[18]))?;
+        let integrator = match bytes[19] {
+            0 => Integrator::Euler,
+            other => return Err(ContainerError::UnknownIntegrator(other)),
+        };
+        if bytes[20] == 0 {
+            return Err(ContainerError::NotAContainer);
+        }
+
+        Ok(Header {
+            kdf,
+            attractor,
+            integrator,
+            trajectories: bytes[20],
+            salt: bytes[21..37].try_into().unwrap(),
+            nonce: bytes[37..53].try_into().unwrap(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ContainerError {
+    Io(io::Error),
+    NotAContainer,
+    UnsupportedVersion(u8),
+    UnknownKdf(u8),
+    /// The header asks for more Argon2 memory, time or lanes than we allow
+    CostsTooHigh,
+    UnknownAttractor(u8),
+    UnknownIntegrator(u8),
+    /// The attractor has fewer than `MIN_DIMENSION` coordinates
+    TooFewDimensions(AttractorKind),
+    /// The credential doesn't fit the kind of key derivation in the header
+    WrongCredential,
+    Kdf(argon2::Error),
+    /// The header MAC doesn't match: a wrong password or key file, or a damaged header
+    WrongKey,
+    /// The ciphertext was changed or cut short
+    Corrupted,
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::Io(e) => write!(f, "{}", e),
+            ContainerError::NotAContainer => write!(f, "not a strange cipher file"),
+            ContainerError::UnsupportedVersion(version) => {
+                write!(f, "unsupported file version {}", version)
+            }
+            ContainerError::UnknownKdf(kdf) => write!(f, "unknown key derivation {}", kdf),
+            ContainerError::CostsTooHigh => {
+                write!(f, "the file asks for an unreasonably costly key derivation")
+            }
+            ContainerError::UnknownAttractor(kind) => write!(f, "unknown attractor {}", kind),
+            ContainerError::UnknownIntegrator(integrator) => {
+                write!(f, "unknown integrator {}", integrator)
+            }
+            ContainerError::TooFewDimensions(kind) => write!(
+                f,
+                "the {} map has too few dimensions to encrypt files with",
+                kind
+            ),
+            ContainerError::WrongCredential => {
+                write!(
+                    f,
+                    "the file was encrypted with the other kind of credential"
+                )
+            }
+            ContainerError::Kdf(e) => write!(f, "key derivation failed: {}", e),
+            ContainerError::WrongKey => write!(f, "wrong password or key file"),
+            ContainerError::Corrupted => write!(f, "the file is damaged or was tampered with"),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
+
+impl From<io::Error> for ContainerError {
+    fn from(e: io::Error) -> Self {
+        ContainerError::Io(e)
+    }
+}
+
+// the secret that picks the attractor's parameters and seeds its trajectories, and
+// the MAC key, both bound to the file's nonce
+struct FileKeys {
+    secret: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+fn derive_keys(header: &Header, credential: &Credential) -> Result<FileKeys, ContainerError> {
+    let mut master = [0; 32];
+    match (header.kdf, credential) {
+        (
+            Kdf::Password {
+                m_cost,
+                t_cost,
+                p_cost,
+            },
+            Credential::Password(password),
+        ) => {
+            let params =
+                Params::new(m_cost, t_cost, p_cost, Some(32)).map_err(ContainerError::Kdf)?;
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(password, &header.salt, &mut master)
+                .map_err(ContainerError::Kdf)?;
+        }
+        (Kdf::KeyFile, Credential::KeyFile(contents)) => {
+            let mut mac = new_mac(&header.salt);
+            mac.update(b"key file");
+            mac.update(contents);
+            master = mac.finalize().into_bytes().into();
+        }
+        _ => return Err(ContainerError::WrongCredential),
+    }
+
+    let expand = |label: &[u8]| -> [u8; 32] {
+        let mut mac = new_mac(&master);
+        mac.update(label);
+        mac.update(&header.nonce);
+        mac.finalize().into_bytes().into()
+    };
+    Ok(FileKeys {
+        secret: expand(b"attractor secret"),
+        mac_key: expand(b"mac key"),
+    })
+}
+
+fn new_mac(key: &[u8]) -> HmacSha256 {
+    <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length")
+}
+
+// The attractor's key bytes XORed with HMAC blocks over the file's secret and
+// nonce. Raw key bytes are slices of `f64`s, whose sign and exponent bytes only take
+// a handful of values and would let the same bits of the plaintext show through
+struct Whitened<K> {
+    inner: K,
+    secret: [u8; 32],
+    nonce: [u8; 16],
+    // bytes handed out so far, which picks the mask block
+    position: u64,
+}
+
+impl<K> Whitened<K> {
+    fn mask_block(&self, block: u64) -> [u8; MASK_BLOCK_LEN] {
+        let mut mac = new_mac(&self.secret);
+        mac.update(b"file mask");
+        mac.update(&self.nonce);
+        mac.update(&block.to_le_bytes());
+        mac.finalize().into_bytes().into()
+    }
+}
+
+impl<K: KeyStream> KeyStream for Whitened<K> {
+    fn fill(&mut self, key: &mut [u8]) {
+        self.inner.fill(key);
+        let mut block = self.position / MASK_BLOCK_LEN as u64;
+        let mut mask = self.mask_block(block);
+        for byte in key.iter_mut() {
+            if self.position / MASK_BLOCK_LEN as u64 != block {
+                block += 1;
+                mask = self.mask_block(block);
+            }
+            *byte ^= mask[self.position as usize % MASK_BLOCK_LEN];
+            self.position += 1;
+        }
+    }
+}
+
+// MACs everything written through it
+struct MacWriter<W> {
+    inner: W,
+    mac: HmacSha256,
+}
+
+impl<W: Write> Write for MacWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.mac.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// MACs everything read through it, holding the last `TAG_LEN` bytes back as the tag
+struct MacReader<R> {
+    inner: R,
+    mac: HmacSha256,
+    held: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Read for MacReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0; 8192];
+        while self.held.len() <= TAG_LEN && !self.done {
+            match self.inner.read(&mut chunk)? {
+                0 => self.done = true,
+                read => self.held.extend_from_slice(&chunk[..read]),
+            }
+        }
+
+        let read = self.held.len().saturating_sub(TAG_LEN).min(buf.len());
+        buf[..read].copy_from_slice(&self.held[..read]);
+        self.mac.update(&self.held[..read]);
+        self.held.drain(..read);
+        Ok(read)
+    }
+}
+
+fn key_stream<'a>(
+    attractor: &'a dyn Attractor,
+    keys: &FileKeys,
+    header: &Header,
+) -> Whitened<ParallelKeyStream<'a>> {
+    Whitened {
+        inner: ParallelKeyStream::new(
+            attractor,
+            &keys.secret,
+            &attractor.initial_state(),
+            header.trajectories as usize,
+        ),
+        secret: keys.secret,
+        nonce: header.nonce,
+        position: 0,
+    }
+}
+
+/// Encrypts all of `input` into `output` as a self-describing container
+pub fn encrypt<R: Read, W: Write>(
+    input: &mut R,
+    output: W,
+    header: &Header,
+    credential: &Credential,
+) -> Result<(), ContainerError> {
+    if header.attractor.build(&[0; 32]).dimension() < MIN_DIMENSION {
+        return Err(ContainerError::TooFewDimensions(header.attractor));
+    }
+    let keys = derive_keys(header, credential)?;
+    let attractor = header.attractor.build(&keys.secret);
+
+    let mut header_bytes = header.to_bytes();
+    let mut mac = new_mac(&keys.mac_key);
+    mac.update(&header_bytes);
+    header_bytes.extend_from_slice(&mac.finalize().into_bytes());
+
+    let mut mac = new_mac(&keys.mac_key);
+    mac.update(&header_bytes);
+    let mut output = MacWriter { inner: output, mac };
+    output.inner.write_all(&header_bytes)?;
+
+    let key_stream = key_stream(attractor.as_ref(), &keys, header);
+    let mut writer = EncryptWriter::new(output, key_stream);
+    io::copy(input, &mut writer)?;
+
+    let MacWriter { mut inner, mac } = writer.into_inner()?;
+    inner.write_all(&mac.finalize().into_bytes())?;
+    inner.flush()?;
+    Ok(())
+}
+
+/// Decrypts a container from `input` into `output`. Plaintext is written as it is
+/// decrypted and only checked at the end, so on `Corrupted` the caller has to throw
+/// away whatever reached `output`
+pub fn decrypt<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    credential: &Credential,
+) -> Result<Header, ContainerError> {
+    let mut header_bytes = [0; HEADER_LEN];
+    let mut header_mac = [0; TAG_LEN];
+    input
+        .read_exact(&mut header_bytes)
+        .and_then(|_| input.read_exact(&mut header_mac))
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => ContainerError::NotAContainer,
+            _ => ContainerError::Io(e),
+        })?;
+    let header = Header::from_bytes(&header_bytes)?;
+
+    let keys = derive_keys(&header, credential)?;
+    let mut mac = new_mac(&keys.mac_key);
+    mac.update(&header_bytes);
+    mac.verify_slice(&header_mac)
+        .map_err(|_| ContainerError::WrongKey)?;
+
+    let attractor = header.attractor.build(&keys.secret);
+    let key_stream = key_stream(attractor.as_ref(), &keys, &header);
+
+    let mut mac = new_mac(&keys.mac_key);
+    mac.update(&header_bytes);
+    mac.update(&header_mac);
+    let mut reader = DecryptReader::new(
+        MacReader {
+            inner: input,
+            mac,
+            held: Vec::new(),
+            done: false,
+        },
+        key_stream,
+    );
+    io::copy(&mut reader, output)?;
+    output.flush()?;
+
+    let MacReader { mac, held, .. } = reader.into_inner();
+    if held.len() != TAG_LEN || mac.verify_slice(&held).is_err() {
+        return Err(ContainerError::Corrupted);
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const KEY_FILE: [u8; 32] = [7; 32];
+
+    // the smallest Argon2 costs, so the tests don't spend seconds hashing
+    fn cheap_password() -> Kdf {
+        Kdf::Password {
+            m_cost: Params::MIN_M_COST,
+            t_cost: 1,
+            p_cost: 1,
+        }
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 % 256) as u8).collect()
+    }
+
+    fn seal(message: &[u8], header: &Header, credential: &Credential) -> Vec<u8> {
+        let mut container = Vec::new();
+        encrypt(&mut &message[..], &mut container, header, credential).unwrap();
+        container
+    }
+
+    fn open(container: &[u8], credential: &Credential) -> Result<Vec<u8>, ContainerError> {
+        let mut plaintext = Vec::new();
+        decrypt(&mut &container[..], &mut plaintext, credential)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_zeros_encrypt_to_evenly_spread_bytes() {
+        let header = Header::new(Kdf::KeyFile, AttractorKind::Lorenz, 4);
+        let container = seal(&[0; 4096], &header, &Credential::KeyFile(&KEY_FILE));
+        let body = &container[HEADER_LEN + TAG_LEN..container.len() - TAG_LEN];
+
+        // every byte of an `f64`, the sign and exponent ones too, takes most of the
+        // 256 values over 512 samples
+        for offset in 0..8 {
+            let mut seen = [false; 256];
+            body.iter()
+                .skip(offset)
+                .step_by(8)
+                .for_each(|&byte| seen[byte as usize] = true);
+            let distinct = seen.iter().filter(|&&seen| seen).count();
+            assert!(distinct > 180, "byte {}: {} values", offset, distinct);
+        }
+    }
+
+    #[test]
+    fn test_whitened_key_stream_is_the_same_however_it_is_read() {
+        let attractor = AttractorKind::Henon.build(&[3; 32]);
+        let keys = FileKeys {
+            secret: [3; 32],
+            mac_key: [4; 32],
+        };
+        let header = Header::new(Kdf::KeyFile, AttractorKind::Henon, 2);
+
+        let mut whole = [0; 100];
+        key_stream(attractor.as_ref(), &keys, &header).fill(&mut whole);
+        let mut pieces = [0; 100];
+        let mut stream = key_stream(attractor.as_ref(), &keys, &header);
+        for piece in pieces.chunks_mut(7) {
+            stream.fill(piece);
+        }
+
+        assert_eq!(whole, pieces);
+    }
+
+    #[test]
+    fn test_round_trip_with_every_attractor() {
+        let message = message(20_000);
+
+        for kind in [
+            AttractorKind::Lorenz,
+            AttractorKind::HyperLorenz,
+            AttractorKind::Henon,
+            AttractorKind::ArnoldCat,
+        ] {
+            let header = Header::new(Kdf::KeyFile, kind, 2);
+            let container = seal(&message, &header, &Credential::KeyFile(&KEY_FILE));
+
+            assert_eq!(
+                container.len(),
+                HEADER_LEN + TAG_LEN + message.len() + TAG_LEN
+            );
+            assert_ne!(container[HEADER_LEN + TAG_LEN..][..64], message[..64]);
+            assert_eq!(
+                open(&container, &Credential::KeyFile(&KEY_FILE)).unwrap(),
+                message,
+                "{}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn test_one_dimensional_maps_are_refused() {
+        for kind in [AttractorKind::Logistic, AttractorKind::Tent] {
+            let header = Header::new(Kdf::KeyFile, kind, 2);
+            let mut container = Vec::new();
+            let result = encrypt(
+                &mut &message(100)[..],
+                &mut container,
+                &header,
+                &Credential::KeyFile(&KEY_FILE),
+            );
+
+            assert!(
+                matches!(result, Err(ContainerError::TooFewDimensions(k)) if k == kind),
+                "{}",
+                kind
+            );
+            assert!(container.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_password_round_trip() {
+        let message = message(1000);
+        let header = Header::new(cheap_password(), AttractorKind::Lorenz, 1);
+        let container = seal(&message, &header, &Credential::Password(b"hunter2"));
+
+        assert_eq!(
+            open(&container, &Credential::Password(b"hunter2")).unwrap(),
+            message
+        );
+        assert!(matches!(
+            open(&container, &Credential::Password(b"hunter3")),
+            Err(ContainerError::WrongKey)
+        ));
+        assert!(matches!(
+            open(&container, &Credential::KeyFile(&KEY_FILE)),
+            Err(ContainerError::WrongCredential)
+        ));
+    }
+
+    #[test]
+    fn test_inflated_costs_are_refused() {
+        let message = message(100);
+        let header = Header::new(cheap_password(), AttractorKind::Lorenz, 1);
+        let container = seal(&message, &header, &Credential::Password(b"hunter2"));
+
+        // m_cost, t_cost and p_cost, each pushed far past the defaults
+        for (offset, cost) in [(6, u32::MAX), (10, 1 << 20), (14, 1000)] {
+            let mut inflated = container.clone();
+            inflated[offset..offset + 4].copy_from_slice(&cost.to_le_bytes());
+            assert!(matches!(
+                open(&inflated, &Credential::Password(b"hunter2")),
+                Err(ContainerError::CostsTooHigh)
+            ));
+        }
+
+        let mut header = Header::new(Kdf::password(), AttractorKind::Lorenz, 1);
+        let bytes: [u8; HEADER_LEN] = header.to_bytes().try_into().unwrap();
+        assert_eq!(Header::from_bytes(&bytes).unwrap(), header);
+        header.kdf = Kdf::Password {
+            m_cost: Params::DEFAULT_M_COST * MAX_COST_FACTOR + 1,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        };
+        let bytes: [u8; HEADER_LEN] = header.to_bytes().try_into().unwrap();
+        assert!(matches!(
+            Header::from_bytes(&bytes),
+            Err(ContainerError::CostsTooHigh)
+        ));
+    }
+
+    #[test]
+    fn test_same_input_encrypts_differently_every_time() {
+        let message = message(1000);
+        let credential = Credential::KeyFile(&KEY_FILE);
+
+        let first = seal(
+            &message,
+            &Header::new(Kdf::KeyFile, AttractorKind::Lorenz, 1),
+            &credential,
+        );
+        let second = seal(
+            &message,
+            &Header::new(Kdf::KeyFile, AttractorKind::Lorenz, 1),
+            &credential,
+        );
+        assert_ne!(
+            first[HEADER_LEN + TAG_LEN..],
+            second[HEADER_LEN + TAG_LEN..]
+        );
+    }
+
+    #[test]
+    fn test_damage_is_detected() {
+        let message = message(5000);
+        let credential = Credential::KeyFile(&KEY_FILE);
+        let header = Header::new(Kdf::KeyFile, AttractorKind::Lorenz, 1);
+        let container = seal(&message, &header, &credential);
+
+        let mut flipped = container.clone();
+        flipped[HEADER_LEN + TAG_LEN + 100] ^= 1;
+        assert!(matches!(
+            open(&flipped, &credential),
+            Err(ContainerError::Corrupted)
+        ));
+
+        let truncated = &container[..container.len() - 1];
+        assert!(matches!(
+            open(truncated, &credential),
+            Err(ContainerError::Corrupted)
+        ));
+
+        let mut header_flipped = container.clone();
+        header_flipped[25] ^= 1;
+        assert!(matches!(
+            open(&header_flipped, &credential),
+            Err(ContainerError::WrongKey)
+        ));
+
+        let mut other_version = container.clone();
+        other_version[4] = 9;
+        assert!(matches!(
+            open(&other_version, &credential),
+            Err(ContainerError::UnsupportedVersion(9))
+        ));
+
+        assert!(matches!(
+            open(b"SCIF", &credential),
+            Err(ContainerError::NotAContainer)
+        ));
+        assert!(matches!(
+            open(&[0; 200], &credential),
+            Err(ContainerError::NotAContainer)
+        ));
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let credential = Credential::KeyFile(&KEY_FILE);
+        let header = Header::new(Kdf::KeyFile, AttractorKind::Henon, 1);
+        let container = seal(&[], &header, &credential);
+
+        assert_eq!(open(&container, &credential).unwrap(), Vec::<u8>::new());
+    }
+}
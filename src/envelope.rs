@@ -0,0 +1,515 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::nonce::{Nonce, NONCE_LEN};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const VERSION: u8 = 1;
+const BINARY_HEADER_LEN: usize = 1 + 16 + NONCE_LEN + 8 + 8 + 8 + 1 + 32;
+/// How many counters below the highest one seen are still accepted out of order
+pub const REPLAY_WINDOW: u64 = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContentType {
+    /// UTF-8 text, what the client reads from stdin
+    Text = 0,
+    Bytes = 1,
+}
+
+impl ContentType {
+    pub fn from_byte(byte: u8) -> Option<ContentType> {
+        match byte {
+            0 => Some(ContentType::Text),
+            1 => Some(ContentType::Bytes),
+            _ => None,
+        }
+    }
+}
+
+/// A ciphertext with everything the receiver needs to check and decrypt it: which
+/// session it belongs to and its place in it, the nonce its key stream was started
+/// from and where in that key stream it starts, and a MAC keyed by the handshake
+/// secret over all of it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub version: u8,
+    #[serde(with = "bytes")]
+    pub session_id: [u8; 16],
+    /// Mixed into the synced state to start this message's key stream, see `nonce`
+    #[serde(with = "bytes")]
+    pub nonce: Nonce,
+    /// Counts the session's messages up from 0, so a replayed one stands out
+    pub counter: u64,
+    /// Where the key used for this message starts in the key stream generated from
+    /// the nonce
+    pub key_stream_offset: u64,
+    pub length: u64,
+    pub content_type: ContentType,
+    #[serde(with = "bytes")]
+    pub ciphertext: Vec<u8>,
+    #[serde(with = "bytes")]
+    pub mac: [u8; 32],
+}
+
+/// Names the session without giving away the secret it is derived from
+pub fn session_id(secret: &[u8; 32]) -> [u8; 16] {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(b"session id");
+    mac.finalize().into_bytes()[..16].try_into().unwrap()
+}
+
+impl Envelope {
+    pub fn seal(
+        secret: &[u8; 32],
+        nonce: Nonce,
+        counter: u64,
+        key_stream_offset: u64,
+        content_type: ContentType,
+        ciphertext: Vec<u8>,
+    ) -> Envelope {
+        let mut envelope = Envelope {
+            version: VERSION,
+            session_id: session_id(secret),
+            nonce,
+            counter,
+            key_stream_offset,
+            length: ciphertext.len() as u64,
+            content_type,
+            ciphertext,
+            mac: [0; 32],
+        };
+        envelope.mac = envelope.compute_mac(secret).finalize().into_bytes().into();
+        envelope
+    }
+
+    fn compute_mac(&self, secret: &[u8; 32]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+        mac.update(b"envelope");
+        mac.update(&[self.version]);
+        mac.update(&self.session_id);
+        mac.update(&self.nonce);
+        mac.update(&self.counter.to_le_bytes());
+        mac.update(&self.key_stream_offset.to_le_bytes());
+        mac.update(&self.length.to_le_bytes());
+        mac.update(&[self.content_type as u8]);
+        mac.update(&self.ciphertext);
+        mac
+    }
+
+    /// Checks the envelope was sealed for this session and hasn't been changed since
+    pub fn verify(&self, secret: &[u8; 32]) -> Result<(), EnvelopeError> {
+        if self.version != VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(self.version));
+        }
+        if self.length != self.ciphertext.len() as u64 {
+            return Err(EnvelopeError::LengthMismatch {
+                declared: self.length,
+                actual: self.ciphertext.len(),
+            });
+        }
+        if self.session_id != session_id(secret) {
+            return Err(EnvelopeError::WrongSession);
+        }
+        self.compute_mac(secret)
+            .verify_slice(&self.mac)
+            .map_err(|_| EnvelopeError::BadMac)
+    }
+
+    /// `[version] [session id; 16] [nonce; 16] [counter: u64] [key stream offset: u64]
+    /// [length: u64] [content type] [MAC; 32] [ciphertext]`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.version];
+        bytes.extend_from_slice(&self.session_id);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.counter.to_le_bytes());
+        bytes.extend_from_slice(&self.key_stream_offset.to_le_bytes());
+        bytes.extend_from_slice(&self.length.to_le_bytes());
+        bytes.push(self.content_type as u8);
+        bytes.extend_from_slice(&self.mac);
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Envelope> {
+        if bytes.len() < BINARY_HEADER_LEN {
+            return None;
+        }
+
+        Some(Envelope {
+            version: bytes[0],
+            session_id: bytes[1..17].try_into().unwrap(),
+            nonce: bytes[17..33].try_into().unwrap(),
+            counter: u64::from_le_bytes(bytes[33..41].try_into().unwrap()),
+            key_stream_offset: u64::from_le_bytes(bytes[41..49].try_into().unwrap()),
+            length: u64::from_le_bytes(bytes[49..57].try_into().unwrap()),
+            content_type: ContentType::from_byte(bytes[57])?,
+            mac: bytes[58..90].try_into().unwrap(),
+            ciphertext: bytes[BINARY_HEADER_LEN..].to_vec(),
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("An envelope is always valid JSON")
+    }
+
+    pub fn from_json(json: &str) -> Option<Envelope> {
+        serde_json::from_str(json).ok()
+    }
+
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes).expect("Writing to a Vec can't fail");
+        bytes
+    }
+
+    pub fn from_cbor(bytes: &[u8]) -> Option<Envelope> {
+        ciborium::from_reader(bytes).ok()
+    }
+}
+
+/// How an envelope is put on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Binary = 0,
+    Json = 1,
+    Cbor = 2,
+}
+
+impl Encoding {
+    pub fn from_byte(byte: u8) -> Option<Encoding> {
+        match byte {
+            0 => Some(Encoding::Binary),
+            1 => Some(Encoding::Json),
+            2 => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "binary" => Some(Encoding::Binary),
+            "json" => Some(Encoding::Json),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self, envelope: &Envelope) -> Vec<u8> {
+        match self {
+            Encoding::Binary => envelope.to_bytes(),
+            Encoding::Json => envelope.to_json().into_bytes(),
+            Encoding::Cbor => envelope.to_cbor(),
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Option<Envelope> {
+        match self {
+            Encoding::Binary => Envelope::from_bytes(bytes),
+            Encoding::Json => std::str::from_utf8(bytes)
+                .ok()
+                .and_then(Envelope::from_json),
+            Encoding::Cbor => Envelope::from_cbor(bytes),
+        }
+    }
+
+    /// Decodes an envelope and checks it belongs to this session
+    pub fn open(&self, bytes: &[u8], secret: &[u8; 32]) -> Result<Envelope, EnvelopeError> {
+        let envelope = self.decode(bytes).ok_or(EnvelopeError::Malformed)?;
+        envelope.verify(secret)?;
+        Ok(envelope)
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Binary => write!(f, "binary"),
+            Encoding::Json => write!(f, "json"),
+            Encoding::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+/// The counters a session has already delivered: the highest one, and a bit for
+/// each of the `REPLAY_WINDOW` below it. Anything older than that is refused
+/// outright, since it can't be told apart from a replay
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    // bit `i` is set once `highest - i` has been delivered
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow::default()
+    }
+
+    /// Marks `counter` as delivered, unless it already was or is too old to tell.
+    /// Only call it once the envelope's MAC checked out, or a forged counter could
+    /// push the window past the real ones
+    pub fn accept(&mut self, counter: u64) -> Result<(), EnvelopeError> {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(counter);
+                self.seen = 1;
+                return Ok(());
+            }
+        };
+
+        if counter > highest {
+            let shift = counter - highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = Some(counter);
+            return Ok(());
+        }
+
+        let age = highest - counter;
+        if age >= REPLAY_WINDOW {
+            return Err(EnvelopeError::Stale(counter));
+        }
+        if self.seen & (1 << age) != 0 {
+            return Err(EnvelopeError::Replayed(counter));
+        }
+        self.seen |= 1 << age;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EnvelopeError {
+    Malformed,
+    UnsupportedVersion(u8),
+    LengthMismatch {
+        declared: u64,
+        actual: usize,
+    },
+    WrongSession,
+    BadMac,
+    /// The session already delivered a message with this counter
+    Replayed(u64),
+    /// The counter is too far behind the newest one to tell if it was delivered
+    Stale(u64),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Malformed => write!(f, "malformed envelope"),
+            EnvelopeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported envelope version {}", version)
+            }
+            EnvelopeError::LengthMismatch { declared, actual } => write!(
+                f,
+                "the envelope declares {} bytes of ciphertext but carries {}",
+                declared, actual
+            ),
+            EnvelopeError::WrongSession => write!(f, "the envelope belongs to another session"),
+            EnvelopeError::BadMac => write!(f, "the envelope's MAC doesn't match"),
+            EnvelopeError::Replayed(counter) => {
+                write!(
+                    f,
+                    "message {} was already delivered, dropped a replay",
+                    counter
+                )
+            }
+            EnvelopeError::Stale(counter) => {
+                write!(f, "message {} is too old to check against replays", counter)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+// Byte fields as base64 strings in JSON and as byte strings in CBOR
+mod bytes {
+    use std::fmt;
+
+    use base64::prelude::*;
+    use serde::{
+        de::{self, Visitor},
+        Deserialize, Deserializer, Serializer,
+    };
+
+    pub fn serialize<S, T>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: AsRef<[u8]>,
+    {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes.as_ref())
+        }
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: TryFrom<Vec<u8>>,
+    {
+        let bytes = if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            BASE64_STANDARD.decode(text).map_err(de::Error::custom)?
+        } else {
+            deserializer.deserialize_byte_buf(ByteBuf)?
+        };
+        let len = bytes.len();
+        T::try_from(bytes).map_err(|_| de::Error::invalid_length(len, &"a fixed number of bytes"))
+    }
+
+    struct ByteBuf;
+
+    impl<'de> Visitor<'de> for ByteBuf {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a byte string")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use base64::prelude::*;
+
+    const SECRET: [u8; 32] = [9; 32];
+
+    fn envelope() -> Envelope {
+        Envelope::seal(
+            &SECRET,
+            [3; NONCE_LEN],
+            7,
+            16,
+            ContentType::Text,
+            b"not really secret".to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_every_encoding_round_trips() {
+        let envelope = envelope();
+
+        for encoding in [Encoding::Binary, Encoding::Json, Encoding::Cbor] {
+            let bytes = encoding.encode(&envelope);
+            assert_eq!(
+                encoding.open(&bytes, &SECRET),
+                Ok(envelope.clone()),
+                "{}",
+                encoding
+            );
+        }
+
+        let json = envelope.to_json();
+        assert!(json.contains("\"content_type\":\"text\""), "{}", json);
+        assert!(json.contains("\"key_stream_offset\":16"), "{}", json);
+    }
+
+    #[test]
+    fn test_changes_are_caught() {
+        let mut offset = envelope();
+        offset.key_stream_offset += 1;
+        assert_eq!(offset.verify(&SECRET), Err(EnvelopeError::BadMac));
+
+        let mut counter = envelope();
+        counter.counter += 1;
+        assert_eq!(counter.verify(&SECRET), Err(EnvelopeError::BadMac));
+
+        let mut nonce = envelope();
+        nonce.nonce[0] ^= 1;
+        assert_eq!(nonce.verify(&SECRET), Err(EnvelopeError::BadMac));
+
+        let mut ciphertext = envelope();
+        ciphertext.ciphertext[0] ^= 1;
+        assert_eq!(ciphertext.verify(&SECRET), Err(EnvelopeError::BadMac));
+
+        let mut truncated = envelope();
+        truncated.ciphertext.pop();
+        assert!(matches!(
+            truncated.verify(&SECRET),
+            Err(EnvelopeError::LengthMismatch { .. })
+        ));
+
+        let mut version = envelope();
+        version.version = 2;
+        assert_eq!(
+            version.verify(&SECRET),
+            Err(EnvelopeError::UnsupportedVersion(2))
+        );
+
+        assert_eq!(
+            envelope().verify(&[8; 32]),
+            Err(EnvelopeError::WrongSession)
+        );
+    }
+
+    #[test]
+    fn test_malformed_input_is_rejected() {
+        let binary = envelope().to_bytes();
+        assert_eq!(
+            Encoding::Binary.open(&binary[..BINARY_HEADER_LEN - 1], &SECRET),
+            Err(EnvelopeError::Malformed)
+        );
+        assert_eq!(
+            Encoding::Json.open(b"{\"version\":1}", &SECRET),
+            Err(EnvelopeError::Malformed)
+        );
+        assert_eq!(
+            Encoding::Cbor.open(&binary, &SECRET),
+            Err(EnvelopeError::Malformed)
+        );
+
+        let short_mac = envelope()
+            .to_json()
+            .replace(&BASE64_STANDARD.encode(envelope().mac), "AAAA");
+        assert_eq!(Envelope::from_json(&short_mac), None);
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.accept(0), Ok(()));
+        assert_eq!(window.accept(0), Err(EnvelopeError::Replayed(0)));
+
+        // later messages may overtake earlier ones, but each gets in once
+        assert_eq!(window.accept(5), Ok(()));
+        assert_eq!(window.accept(3), Ok(()));
+        assert_eq!(window.accept(3), Err(EnvelopeError::Replayed(3)));
+        assert_eq!(window.accept(5), Err(EnvelopeError::Replayed(5)));
+        assert_eq!(window.accept(1), Ok(()));
+
+        assert_eq!(window.accept(5 + REPLAY_WINDOW), Ok(()));
+        assert_eq!(window.accept(5), Err(EnvelopeError::Stale(5)));
+        assert_eq!(window.accept(6), Ok(()));
+        assert_eq!(window.accept(6), Err(EnvelopeError::Replayed(6)));
+
+        // a jump past the whole window forgets everything below it
+        assert_eq!(window.accept(1000), Ok(()));
+        assert_eq!(window.accept(999), Ok(()));
+        assert_eq!(
+            window.accept(1000 - REPLAY_WINDOW),
+            Err(EnvelopeError::Stale(936))
+        );
+    }
+}
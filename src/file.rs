@@ -0,0 +1,160 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::PathBuf,
+    process,
+};
+
+use strange_cipher::{
+    attractor::AttractorKind,
+    container::{self, Credential, Header, Kdf},
+};
+
+const USAGE: &str = "Usage: strange-cipher file encrypt|decrypt <input> <output> \
+    [--password <password> | --key-file <path>] [--attractor <name>] [--trajectories <K>]";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Encrypt,
+    Decrypt,
+}
+
+struct Options {
+    direction: Direction,
+    input: PathBuf,
+    output: PathBuf,
+    password: Option<String>,
+    key_file: Option<PathBuf>,
+    attractor: Option<AttractorKind>,
+    trajectories: Option<u8>,
+}
+
+fn parse_args() -> Options {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() != Some("file") {
+        panic!("{}", USAGE);
+    }
+    let direction = match args.next().as_deref() {
+        Some("encrypt") => Direction::Encrypt,
+        Some("decrypt") => Direction::Decrypt,
+        _ => panic!("{}", USAGE),
+    };
+    let input = args.next().map(PathBuf::from).expect(USAGE);
+    let output = args.next().map(PathBuf::from).expect(USAGE);
+
+    let mut options = Options {
+        direction,
+        input,
+        output,
+        password: None,
+        key_file: None,
+        attractor: None,
+        trajectories: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--password" => options.password = args.next(),
+            "--key-file" => options.key_file = args.next().map(PathBuf::from),
+            "--attractor" => {
+                let name = args.next().expect("--attractor needs a value");
+                options.attractor = AttractorKind::from_name(&name)
+                    .map(Some)
+                    .unwrap_or_else(|| panic!("Unknown attractor: {}", name));
+            }
+            "--trajectories" => {
+                options.trajectories = args
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .filter(|&count| count > 0)
+                    .map(Some)
+                    .expect("--trajectories needs a number from 1 to 255");
+            }
+            _ => panic!("Unknown argument: {}", arg),
+        }
+    }
+
+    if options.password.is_some() && options.key_file.is_some() {
+        panic!("Give either --password or --key-file, not both");
+    }
+    // the header records both, so decrypting only needs the credential
+    if options.direction == Direction::Decrypt
+        && (options.attractor.is_some() || options.trajectories.is_some())
+    {
+        panic!("--attractor and --trajectories are read from the file when decrypting");
+    }
+
+    options
+}
+
+fn run(options: &Options) -> Result<(), container::ContainerError> {
+    let key_file = match &options.key_file {
+        Some(path) => Some(fs::read(path)?),
+        None => None,
+    };
+    let password = match (&options.password, &key_file) {
+        (Some(password), _) => Some(password.clone()),
+        (None, Some(_)) => None,
+        // keeps the password out of the shell history and process list
+        (None, None) => {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            Some(line.trim_end_matches(['\r', '\n']).to_string())
+        }
+    };
+    let credential = match (&password, &key_file) {
+        (Some(password), _) => Credential::Password(password.as_bytes()),
+        (None, Some(contents)) => Credential::KeyFile(contents),
+        (None, None) => unreachable!(),
+    };
+
+    let mut input = BufReader::new(File::open(&options.input)?);
+    match options.direction {
+        Direction::Encrypt => {
+            let kdf = match credential {
+                Credential::Password(_) => Kdf::password(),
+                Credential::KeyFile(_) => Kdf::KeyFile,
+            };
+            let header = Header::new(
+                kdf,
+                options.attractor.unwrap_or(AttractorKind::Lorenz),
+                options.trajectories.unwrap_or(4),
+            );
+            let output = BufWriter::new(File::create(&options.output)?);
+            container::encrypt(&mut input, output, &header, &credential)
+        }
+        Direction::Decrypt => {
+            // nothing is trusted until the trailing MAC checks out, so the plaintext
+            // only takes the output's name once it does
+            let mut partial = options.output.clone().into_os_string();
+            partial.push(".partial");
+            let mut output = BufWriter::new(File::create(&partial)?);
+
+            match container::decrypt(&mut input, &mut output, &credential) {
+                Ok(header) => {
+                    drop(output);
+                    fs::rename(&partial, &options.output)?;
+                    println!(
+                        "Decrypted with the {} attractor and {} trajectories",
+                        header.attractor, header.trajectories
+                    );
+                    Ok(())
+                }
+                Err(e) => {
+                    drop(output);
+                    let _ = fs::remove_file(&partial);
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+
+pub fn main() {
+    let options = parse_args();
+
+    if let Err(e) = run(&options) {
+        eprintln!("strange-cipher: {}", e);
+        process::exit(1);
+    }
+}
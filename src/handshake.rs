@@ -0,0 +1,1028 @@
+use std::fmt;
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use ml_kem::{
+    kem::{Decapsulate, Encapsulate},
+    Ciphertext, EncodedSizeUser, KemCore, MlKem768,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tungstenite::{Message, WebSocket};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+
+use crate::ticket::{Resumption, StoredTicket, TicketError, TicketKey, TICKET_LEN};
+
+type HmacSha256 = Hmac<Sha256>;
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+const ENCAPSULATION_KEY_LEN: usize = 1184;
+const KEM_CIPHERTEXT_LEN: usize = 1088;
+
+const PROLOGUE: &[u8] = b"strange_cipher";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeMode {
+    /// The original single-message X25519 public key swap
+    Raw = 0,
+    NoiseXX = 1,
+    NoiseIK = 2,
+    /// X25519 combined with ML-KEM-768
+    Hybrid = 3,
+    /// No key exchange: a ticket from an earlier session, see `resume`
+    Resume = 4,
+}
+
+impl HandshakeMode {
+    pub fn from_byte(byte: u8) -> Option<HandshakeMode> {
+        match byte {
+            0 => Some(HandshakeMode::Raw),
+            1 => Some(HandshakeMode::NoiseXX),
+            2 => Some(HandshakeMode::NoiseIK),
+            3 => Some(HandshakeMode::Hybrid),
+            4 => Some(HandshakeMode::Resume),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HandshakeMode> {
+        match name.to_lowercase().as_str() {
+            "raw" => Some(HandshakeMode::Raw),
+            "xx" => Some(HandshakeMode::NoiseXX),
+            "ik" => Some(HandshakeMode::NoiseIK),
+            "hybrid" => Some(HandshakeMode::Hybrid),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Socket(Box<tungstenite::Error>),
+    UnexpectedMessage,
+    UnsupportedMode(u8),
+    MissingServerKey,
+    Decrypt,
+    KeyConfirmationFailed,
+    InvalidKeyLength(usize),
+    InvalidKemMessage(usize),
+    NonContributory,
+    MissingTicket,
+    /// The server couldn't use our ticket
+    TicketRejected,
+    Ticket(TicketError),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Socket(e) => write!(f, "socket error during handshake: {}", e),
+            HandshakeError::UnexpectedMessage => write!(f, "unexpected handshake message"),
+            HandshakeError::UnsupportedMode(mode) => {
+                write!(f, "unsupported handshake mode {}", mode)
+            }
+            HandshakeError::MissingServerKey => {
+                write!(f, "the IK pattern needs the server's static key")
+            }
+            HandshakeError::Decrypt => write!(f, "could not decrypt handshake message"),
+            HandshakeError::InvalidKeyLength(len) => {
+                write!(f, "public keys must be 32 bytes long, got {}", len)
+            }
+            HandshakeError::InvalidKemMessage(len) => {
+                write!(f, "malformed ML-KEM handshake message of {} bytes", len)
+            }
+            HandshakeError::NonContributory => {
+                write!(
+                    f,
+                    "rejected a low-order public key (non-contributory shared secret)"
+                )
+            }
+            HandshakeError::KeyConfirmationFailed => {
+                write!(
+                    f,
+                    "key confirmation failed, the peers derived different secrets"
+                )
+            }
+            HandshakeError::MissingTicket => write!(f, "resuming needs a ticket"),
+            HandshakeError::TicketRejected => write!(f, "the server rejected the ticket"),
+            HandshakeError::Ticket(e) => write!(f, "invalid resumption ticket: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<tungstenite::Error> for HandshakeError {
+    fn from(e: tungstenite::Error) -> Self {
+        HandshakeError::Socket(Box::new(e))
+    }
+}
+
+/// What both peers agree on once the handshake is over
+pub struct HandshakeOutcome {
+    pub chaining_key: [u8; 32],
+    pub handshake_hash: [u8; 32],
+    pub remote_static: Option<PublicKey>,
+    /// The parameter key of the session a ticket resumed
+    pub restored: Option<[u8; 32]>,
+}
+
+impl HandshakeOutcome {
+    /// The secret the attractor's parameters are drawn from: the chaining key, unless
+    /// a ticket brought back an earlier session's
+    pub fn parameter_key(&self) -> [u8; 32] {
+        self.restored.unwrap_or(self.chaining_key)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+    SS,
+}
+
+#[derive(Clone, Copy)]
+pub enum Pattern {
+    XX,
+    IK,
+}
+
+impl Pattern {
+    fn name(&self) -> &'static [u8] {
+        match self {
+            Pattern::XX => b"Noise_XX_25519_ChaChaPoly_SHA256",
+            Pattern::IK => b"Noise_IK_25519_ChaChaPoly_SHA256",
+        }
+    }
+
+    fn messages(&self) -> &'static [&'static [Token]] {
+        use Token::*;
+        match self {
+            Pattern::XX => &[&[E], &[E, EE, S, ES], &[S, SE]],
+            Pattern::IK => &[&[E, ES, S, SS], &[E, EE, SE]],
+        }
+    }
+}
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    data.iter().for_each(|d| mac.update(d));
+    mac.finalize().into_bytes().into()
+}
+
+fn hkdf(chaining_key: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let temp_key = hmac(chaining_key, &[ikm]);
+    let out1 = hmac(&temp_key, &[&[1]]);
+    let out2 = hmac(&temp_key, &[&out1, &[2]]);
+    (out1, out2)
+}
+
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+    k: Option<[u8; 32]>,
+    n: u64,
+}
+
+impl SymmetricState {
+    fn new(protocol_name: &[u8]) -> SymmetricState {
+        let mut h = [0; 32];
+        if protocol_name.len() <= 32 {
+            h[..protocol_name.len()].copy_from_slice(protocol_name);
+        } else {
+            h = Sha256::digest(protocol_name).into();
+        }
+
+        SymmetricState {
+            ck: h,
+            h,
+            k: None,
+            n: 0,
+        }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.h);
+        hasher.update(data);
+        self.h = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let (ck, k) = hkdf(&self.ck, ikm);
+        self.ck = ck;
+        self.k = Some(k);
+        self.n = 0;
+    }
+
+    fn nonce(&self) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.n.to_le_bytes());
+        Nonce::from(nonce)
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match self.k {
+            Some(k) => {
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&k));
+                let payload = Payload {
+                    msg: plaintext,
+                    aad: &self.h,
+                };
+                let ciphertext = cipher
+                    .encrypt(&self.nonce(), payload)
+                    .expect("ChaCha20Poly1305 encryption does not fail");
+                self.n += 1;
+                ciphertext
+            }
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let plaintext = match self.k {
+            Some(k) => {
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&k));
+                let payload = Payload {
+                    msg: ciphertext,
+                    aad: &self.h,
+                };
+                let plaintext = cipher
+                    .decrypt(&self.nonce(), payload)
+                    .map_err(|_| HandshakeError::Decrypt)?;
+                self.n += 1;
+                plaintext
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    fn tag_len(&self) -> usize {
+        if self.k.is_some() {
+            16
+        } else {
+            0
+        }
+    }
+}
+
+pub struct HandshakeState {
+    symmetric: SymmetricState,
+    pattern: Pattern,
+    initiator: bool,
+    s: StaticSecret,
+    e: Option<StaticSecret>,
+    rs: Option<PublicKey>,
+    re: Option<PublicKey>,
+    index: usize,
+}
+
+impl HandshakeState {
+    pub fn initiator(
+        pattern: Pattern,
+        s: StaticSecret,
+        rs: Option<PublicKey>,
+    ) -> Result<HandshakeState, HandshakeError> {
+        let mut symmetric = SymmetricState::new(pattern.name());
+        symmetric.mix_hash(PROLOGUE);
+        let rs = match pattern {
+            Pattern::XX => None,
+            Pattern::IK => {
+                let rs = rs.ok_or(HandshakeError::MissingServerKey)?;
+                symmetric.mix_hash(rs.as_bytes());
+                Some(rs)
+            }
+        };
+
+        Ok(HandshakeState {
+            symmetric,
+            pattern,
+            initiator: true,
+            s,
+            e: None,
+            rs,
+            re: None,
+            index: 0,
+        })
+    }
+
+    pub fn responder(pattern: Pattern, s: StaticSecret) -> HandshakeState {
+        let mut symmetric = SymmetricState::new(pattern.name());
+        symmetric.mix_hash(PROLOGUE);
+        if let Pattern::IK = pattern {
+            symmetric.mix_hash(PublicKey::from(&s).as_bytes());
+        }
+
+        HandshakeState {
+            symmetric,
+            pattern,
+            initiator: false,
+            s,
+            e: None,
+            rs: None,
+            re: None,
+            index: 0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.index == self.pattern.messages().len()
+    }
+
+    fn dh(&self, token: Token) -> Result<[u8; 32], HandshakeError> {
+        let missing = || HandshakeError::UnexpectedMessage;
+        let e = || self.e.as_ref().ok_or_else(missing);
+        let re = || self.re.as_ref().ok_or_else(missing);
+        let rs = || self.rs.as_ref().ok_or_else(missing);
+
+        let shared = match (token, self.initiator) {
+            (Token::EE, _) => e()?.diffie_hellman(re()?),
+            (Token::ES, true) => e()?.diffie_hellman(rs()?),
+            (Token::ES, false) => self.s.diffie_hellman(re()?),
+            (Token::SE, true) => self.s.diffie_hellman(re()?),
+            (Token::SE, false) => e()?.diffie_hellman(rs()?),
+            (Token::SS, _) => self.s.diffie_hellman(rs()?),
+            _ => unreachable!("only DH tokens are mixed"),
+        };
+        contributory(shared)
+    }
+
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::new();
+
+        for &token in self.pattern.messages()[self.index] {
+            match token {
+                Token::E => {
+                    let e = StaticSecret::random_from_rng(OsRng);
+                    let e_pub = PublicKey::from(&e);
+                    message.extend_from_slice(e_pub.as_bytes());
+                    self.symmetric.mix_hash(e_pub.as_bytes());
+                    self.e = Some(e);
+                }
+                Token::S => {
+                    let s_pub = PublicKey::from(&self.s);
+                    message.extend(self.symmetric.encrypt_and_hash(s_pub.as_bytes()));
+                }
+                _ => {
+                    let shared = self.dh(token)?;
+                    self.symmetric.mix_key(&shared);
+                }
+            }
+        }
+
+        message.extend(self.symmetric.encrypt_and_hash(payload));
+        self.index += 1;
+        Ok(message)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let mut rest = message;
+
+        for &token in self.pattern.messages()[self.index] {
+            match token {
+                Token::E => {
+                    let (re, tail) = split_key(rest, 32)?;
+                    self.symmetric.mix_hash(re);
+                    self.re = Some(parse_public_key(re)?);
+                    rest = tail;
+                }
+                Token::S => {
+                    let (rs, tail) = split_key(rest, 32 + self.symmetric.tag_len())?;
+                    let rs = self.symmetric.decrypt_and_hash(rs)?;
+                    self.rs = Some(parse_public_key(&rs)?);
+                    rest = tail;
+                }
+                _ => {
+                    let shared = self.dh(token)?;
+                    self.symmetric.mix_key(&shared);
+                }
+            }
+        }
+
+        let payload = self.symmetric.decrypt_and_hash(rest)?;
+        self.index += 1;
+        Ok(payload)
+    }
+
+    pub fn finish(self) -> HandshakeOutcome {
+        HandshakeOutcome {
+            chaining_key: self.symmetric.ck,
+            handshake_hash: self.symmetric.h,
+            remote_static: self.rs,
+            restored: None,
+        }
+    }
+}
+
+fn split_key(bytes: &[u8], len: usize) -> Result<(&[u8], &[u8]), HandshakeError> {
+    if bytes.len() < len {
+        return Err(HandshakeError::UnexpectedMessage);
+    }
+    Ok(bytes.split_at(len))
+}
+
+fn hybrid_transcript(client_message: &[u8], server_message: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(PROLOGUE);
+    hasher.update(b"X25519+ML-KEM-768");
+    hasher.update(client_message);
+    hasher.update(server_message);
+    hasher.finalize().into()
+}
+
+/// Both secrets go through the KDF, so the result holds as long as either one does
+fn hybrid_outcome(
+    dh_secret: &[u8; 32],
+    kem_secret: &[u8],
+    transcript: [u8; 32],
+) -> HandshakeOutcome {
+    let (chaining_key, _) = hkdf(&transcript, &[dh_secret.as_slice(), kem_secret].concat());
+
+    HandshakeOutcome {
+        chaining_key,
+        handshake_hash: transcript,
+        remote_static: None,
+        restored: None,
+    }
+}
+
+/// Client half of the hybrid exchange: an ephemeral X25519 key and an ML-KEM-768
+/// encapsulation key go out, the server's X25519 key and a KEM ciphertext come back
+pub struct HybridInitiator {
+    secret: EphemeralSecret,
+    decapsulation_key: DecapsulationKey,
+    message: Vec<u8>,
+}
+
+impl HybridInitiator {
+    pub fn new() -> HybridInitiator {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut OsRng);
+
+        let mut message = PublicKey::from(&secret).as_bytes().to_vec();
+        message.extend_from_slice(&encapsulation_key.as_bytes());
+
+        HybridInitiator {
+            secret,
+            decapsulation_key,
+            message,
+        }
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    pub fn finish(self, reply: &[u8]) -> Result<HandshakeOutcome, HandshakeError> {
+        if reply.len() != 32 + KEM_CIPHERTEXT_LEN {
+            return Err(HandshakeError::InvalidKemMessage(reply.len()));
+        }
+        let (server_public_key, ciphertext) = reply.split_at(32);
+
+        let dh_secret = contributory(
+            self.secret
+                .diffie_hellman(&parse_public_key(server_public_key)?),
+        )?;
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .map_err(|_| HandshakeError::InvalidKemMessage(reply.len()))?;
+        let kem_secret = self
+            .decapsulation_key
+            .decapsulate(&ciphertext)
+            .map_err(|_| HandshakeError::Decrypt)?;
+
+        Ok(hybrid_outcome(
+            &dh_secret,
+            &kem_secret,
+            hybrid_transcript(&self.message, reply),
+        ))
+    }
+}
+
+impl Default for HybridInitiator {
+    fn default() -> Self {
+        HybridInitiator::new()
+    }
+}
+
+/// Server half of the hybrid exchange, returns the reply to send back
+pub fn hybrid_respond(message: &[u8]) -> Result<(Vec<u8>, HandshakeOutcome), HandshakeError> {
+    if message.len() != 32 + ENCAPSULATION_KEY_LEN {
+        return Err(HandshakeError::InvalidKemMessage(message.len()));
+    }
+    let (client_public_key, encapsulation_key) = message.split_at(32);
+    let client_public_key = parse_public_key(client_public_key)?;
+    let encapsulation_key = EncapsulationKey::from_bytes(
+        &encapsulation_key
+            .try_into()
+            .map_err(|_| HandshakeError::InvalidKemMessage(message.len()))?,
+    );
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let mut reply = PublicKey::from(&secret).as_bytes().to_vec();
+    let dh_secret = contributory(secret.diffie_hellman(&client_public_key))?;
+
+    let (ciphertext, kem_secret) = encapsulation_key
+        .encapsulate(&mut OsRng)
+        .map_err(|_| HandshakeError::InvalidKemMessage(message.len()))?;
+    reply.extend_from_slice(&ciphertext);
+
+    let outcome = hybrid_outcome(&dh_secret, &kem_secret, hybrid_transcript(message, &reply));
+    Ok((reply, outcome))
+}
+
+fn read_binary<S>(socket: &mut WebSocket<S>) -> Result<Vec<u8>, HandshakeError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    match socket.read()? {
+        Message::Binary(bytes) => Ok(bytes),
+        _ => Err(HandshakeError::UnexpectedMessage),
+    }
+}
+
+fn resumed_outcome(
+    resumption: &Resumption,
+    ticket: &[u8],
+    client_random: &[u8; 32],
+    server_random: &[u8; 32],
+) -> HandshakeOutcome {
+    let mut hasher = Sha256::new();
+    hasher.update(PROLOGUE);
+    hasher.update(b"resume");
+    hasher.update(ticket);
+    hasher.update(client_random);
+    hasher.update(server_random);
+
+    HandshakeOutcome {
+        chaining_key: resumption.resumed_key(client_random, server_random),
+        handshake_hash: hasher.finalize().into(),
+        remote_static: None,
+        restored: Some(resumption.parameter_key),
+    }
+}
+
+fn raw_transcript(client_public_key: &PublicKey, server_public_key: &PublicKey) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(PROLOGUE);
+    hasher.update(client_public_key.as_bytes());
+    hasher.update(server_public_key.as_bytes());
+    hasher.finalize().into()
+}
+
+#[derive(Clone, Copy)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn label(&self) -> &'static [u8] {
+        match self {
+            Role::Client => b"client finished",
+            Role::Server => b"server finished",
+        }
+    }
+}
+
+/// MAC over the handshake transcript, keyed by a confirmation key derived from the
+/// agreed secret, so both sides can check they ended up with the same one
+pub fn confirmation_tag(outcome: &HandshakeOutcome, role: Role) -> [u8; 32] {
+    let (confirmation_key, _) = hkdf(&outcome.chaining_key, b"key confirmation");
+    hmac(&confirmation_key, &[role.label(), &outcome.handshake_hash])
+}
+
+pub fn verify_confirmation(
+    outcome: &HandshakeOutcome,
+    role: Role,
+    tag: &[u8],
+) -> Result<(), HandshakeError> {
+    let (confirmation_key, _) = hkdf(&outcome.chaining_key, b"key confirmation");
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&confirmation_key)
+        .expect("HMAC accepts any key length");
+    mac.update(role.label());
+    mac.update(&outcome.handshake_hash);
+    mac.verify_slice(tag)
+        .map_err(|_| HandshakeError::KeyConfirmationFailed)
+}
+
+fn confirm_as_client<S>(
+    socket: &mut WebSocket<S>,
+    outcome: &HandshakeOutcome,
+) -> Result<(), HandshakeError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let tag = confirmation_tag(outcome, Role::Client);
+    socket.send(Message::Binary(tag.to_vec()))?;
+
+    verify_confirmation(outcome, Role::Server, &read_binary(socket)?)
+}
+
+fn confirm_as_server<S>(
+    socket: &mut WebSocket<S>,
+    outcome: &HandshakeOutcome,
+) -> Result<(), HandshakeError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let client_tag = read_binary(socket)?;
+
+    // answer either way so the client finds out about a mismatch on its own
+    let tag = confirmation_tag(outcome, Role::Server);
+    socket.send(Message::Binary(tag.to_vec()))?;
+
+    verify_confirmation(outcome, Role::Client, &client_tag)
+}
+
+/// Checks the length of a received X25519 public key
+pub fn parse_public_key(bytes: &[u8]) -> Result<PublicKey, HandshakeError> {
+    let byte_array: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| HandshakeError::InvalidKeyLength(bytes.len()))?;
+    Ok(PublicKey::from(byte_array))
+}
+
+/// Low-order points force the shared secret to a value the attacker knows,
+/// which `was_contributory` catches
+fn contributory(shared: SharedSecret) -> Result<[u8; 32], HandshakeError> {
+    if shared.was_contributory() {
+        Ok(shared.to_bytes())
+    } else {
+        Err(HandshakeError::NonContributory)
+    }
+}
+
+/// Runs the client side of the handshake. The first frame carries the mode byte
+/// so the server knows which exchange follows.
+pub fn initiate<S>(
+    socket: &mut WebSocket<S>,
+    mode: HandshakeMode,
+    static_key: &StaticSecret,
+    server_key: Option<PublicKey>,
+) -> Result<HandshakeOutcome, HandshakeError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let pattern = match mode {
+        HandshakeMode::Raw => {
+            let client_secret_key = EphemeralSecret::random_from_rng(OsRng);
+            let client_public_key = PublicKey::from(&client_secret_key);
+
+            let mut first = vec![mode as u8];
+            first.extend_from_slice(client_public_key.as_bytes());
+            socket.send(Message::Binary(first))?;
+
+            let server_public_key = parse_public_key(&read_binary(socket)?)?;
+            let shared_secret = contributory(client_secret_key.diffie_hellman(&server_public_key))?;
+
+            let outcome = HandshakeOutcome {
+                chaining_key: shared_secret,
+                handshake_hash: raw_transcript(&client_public_key, &server_public_key),
+                remote_static: None,
+                restored: None,
+            };
+            confirm_as_client(socket, &outcome)?;
+            return Ok(outcome);
+        }
+        HandshakeMode::Hybrid => {
+            let initiator = HybridInitiator::new();
+
+            let mut first = vec![mode as u8];
+            first.extend_from_slice(initiator.message());
+            socket.send(Message::Binary(first))?;
+
+            let outcome = initiator.finish(&read_binary(socket)?)?;
+            confirm_as_client(socket, &outcome)?;
+            return Ok(outcome);
+        }
+        HandshakeMode::NoiseXX => Pattern::XX,
+        HandshakeMode::NoiseIK => Pattern::IK,
+        HandshakeMode::Resume => return Err(HandshakeError::MissingTicket),
+    };
+
+    let mut state = HandshakeState::initiator(pattern, static_key.clone(), server_key)?;
+    let mut first = vec![mode as u8];
+    first.extend(state.write_message(&[])?);
+    socket.send(Message::Binary(first))?;
+
+    state.read_message(&read_binary(socket)?)?;
+    if !state.is_finished() {
+        let last = state.write_message(&[])?;
+        socket.send(Message::Binary(last))?;
+    }
+
+    let outcome = state.finish();
+    confirm_as_client(socket, &outcome)?;
+    Ok(outcome)
+}
+
+/// Picks up an earlier session from a ticket the server issued in it, instead of a
+/// key exchange. Only a client holding the ticket's secret gets through the key
+/// confirmation, so a stolen ticket alone is useless
+pub fn resume<S>(
+    socket: &mut WebSocket<S>,
+    stored: &StoredTicket,
+) -> Result<HandshakeOutcome, HandshakeError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let mut client_random = [0; 32];
+    OsRng.fill_bytes(&mut client_random);
+
+    let mut first = vec![HandshakeMode::Resume as u8];
+    first.extend_from_slice(&stored.ticket);
+    first.extend_from_slice(&client_random);
+    socket.send(Message::Binary(first))?;
+
+    let server_random: [u8; 32] = read_binary(socket)?
+        .try_into()
+        .map_err(|_| HandshakeError::TicketRejected)?;
+
+    let outcome = resumed_outcome(
+        &stored.resumption,
+        &stored.ticket,
+        &client_random,
+        &server_random,
+    );
+    confirm_as_client(socket, &outcome)?;
+    Ok(outcome)
+}
+
+/// Runs the server side of the handshake for whichever mode the client picked
+pub fn respond<S>(
+    socket: &mut WebSocket<S>,
+    static_key: &StaticSecret,
+    ticket_key: &TicketKey,
+) -> Result<HandshakeOutcome, HandshakeError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let first = read_binary(socket)?;
+    let (&mode_byte, message) = first
+        .split_first()
+        .ok_or(HandshakeError::UnexpectedMessage)?;
+
+    let pattern = match HandshakeMode::from_byte(mode_byte) {
+        Some(HandshakeMode::Raw) => {
+            let server_secret_key = EphemeralSecret::random_from_rng(OsRng);
+            let server_public_key = PublicKey::from(&server_secret_key);
+
+            let client_public_key = parse_public_key(message)?;
+            let shared_secret = contributory(server_secret_key.diffie_hellman(&client_public_key))?;
+
+            socket.send(Message::Binary(server_public_key.to_bytes().to_vec()))?;
+
+            let outcome = HandshakeOutcome {
+                chaining_key: shared_secret,
+                handshake_hash: raw_transcript(&client_public_key, &server_public_key),
+                remote_static: None,
+                restored: None,
+            };
+            confirm_as_server(socket, &outcome)?;
+            return Ok(outcome);
+        }
+        Some(HandshakeMode::Hybrid) => {
+            let (reply, outcome) = hybrid_respond(message)?;
+            socket.send(Message::Binary(reply))?;
+
+            confirm_as_server(socket, &outcome)?;
+            return Ok(outcome);
+        }
+        Some(HandshakeMode::Resume) => {
+            let (ticket, client_random) = split_key(message, TICKET_LEN)?;
+            let client_random: [u8; 32] = client_random
+                .try_into()
+                .map_err(|_| HandshakeError::UnexpectedMessage)?;
+
+            let resumption = match ticket_key.redeem(ticket) {
+                Ok(resumption) => resumption,
+                Err(e) => {
+                    // anything but a random tells the client to start over
+                    socket.send(Message::Binary(vec![0]))?;
+                    return Err(HandshakeError::Ticket(e));
+                }
+            };
+
+            let mut server_random = [0; 32];
+            OsRng.fill_bytes(&mut server_random);
+            socket.send(Message::Binary(server_random.to_vec()))?;
+
+            let outcome = resumed_outcome(&resumption, ticket, &client_random, &server_random);
+            confirm_as_server(socket, &outcome)?;
+            return Ok(outcome);
+        }
+        Some(HandshakeMode::NoiseXX) => Pattern::XX,
+        Some(HandshakeMode::NoiseIK) => Pattern::IK,
+        None => return Err(HandshakeError::UnsupportedMode(mode_byte)),
+    };
+
+    let mut state = HandshakeState::responder(pattern, static_key.clone());
+    state.read_message(message)?;
+
+    let reply = state.write_message(&[])?;
+    socket.send(Message::Binary(reply))?;
+
+    if !state.is_finished() {
+        state.read_message(&read_binary(socket)?)?;
+    }
+
+    let outcome = state.finish();
+    confirm_as_server(socket, &outcome)?;
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn run(pattern: Pattern, payloads: bool) -> (HandshakeOutcome, HandshakeOutcome) {
+        let client_static = StaticSecret::random_from_rng(OsRng);
+        let server_static = StaticSecret::random_from_rng(OsRng);
+        let server_public = PublicKey::from(&server_static);
+
+        let mut initiator =
+            HandshakeState::initiator(pattern, client_static, Some(server_public)).unwrap();
+        let mut responder = HandshakeState::responder(pattern, server_static);
+
+        let mut turn = 0;
+        while !initiator.is_finished() {
+            let payload = if payloads {
+                vec![turn as u8; 5]
+            } else {
+                vec![]
+            };
+            let (writer, reader) = if turn % 2 == 0 {
+                (&mut initiator, &mut responder)
+            } else {
+                (&mut responder, &mut initiator)
+            };
+            let message = writer.write_message(&payload).unwrap();
+            assert_eq!(reader.read_message(&message).unwrap(), payload);
+            turn += 1;
+        }
+
+        assert!(responder.is_finished());
+        (initiator.finish(), responder.finish())
+    }
+
+    #[test]
+    fn test_xx_agrees() {
+        let (client, server) = run(Pattern::XX, false);
+        assert_eq!(client.chaining_key, server.chaining_key);
+        assert_eq!(client.handshake_hash, server.handshake_hash);
+        assert!(client.remote_static.is_some());
+        assert!(server.remote_static.is_some());
+    }
+
+    #[test]
+    fn test_ik_agrees_with_payloads() {
+        let (client, server) = run(Pattern::IK, true);
+        assert_eq!(client.chaining_key, server.chaining_key);
+        assert_eq!(client.handshake_hash, server.handshake_hash);
+    }
+
+    #[test]
+    fn test_key_confirmation() {
+        let (client, server) = run(Pattern::XX, false);
+
+        let client_tag = confirmation_tag(&client, Role::Client);
+        let server_tag = confirmation_tag(&server, Role::Server);
+        assert!(verify_confirmation(&server, Role::Client, &client_tag).is_ok());
+        assert!(verify_confirmation(&client, Role::Server, &server_tag).is_ok());
+
+        // a tag is only valid for the role that produced it
+        assert!(verify_confirmation(&server, Role::Server, &client_tag).is_err());
+    }
+
+    #[test]
+    fn test_key_confirmation_mismatch() {
+        let (client, mut server) = run(Pattern::XX, false);
+        server.chaining_key[0] ^= 1;
+
+        let client_tag = confirmation_tag(&client, Role::Client);
+        assert!(matches!(
+            verify_confirmation(&server, Role::Client, &client_tag),
+            Err(HandshakeError::KeyConfirmationFailed)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_wrong_key_length() {
+        assert!(matches!(
+            parse_public_key(&[7; 31]),
+            Err(HandshakeError::InvalidKeyLength(31))
+        ));
+        assert!(matches!(
+            parse_public_key(&[7; 33]),
+            Err(HandshakeError::InvalidKeyLength(33))
+        ));
+        assert!(parse_public_key(&[7; 32]).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_truncated_noise_message() {
+        let mut responder =
+            HandshakeState::responder(Pattern::XX, StaticSecret::random_from_rng(OsRng));
+        assert!(matches!(
+            responder.read_message(&[1; 20]),
+            Err(HandshakeError::UnexpectedMessage)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_low_order_key() {
+        let mut responder =
+            HandshakeState::responder(Pattern::XX, StaticSecret::random_from_rng(OsRng));
+
+        // the all-zero point has order 1, so DH with it is never contributory
+        responder.read_message(&[0; 32]).unwrap();
+        assert!(matches!(
+            responder.write_message(&[]),
+            Err(HandshakeError::NonContributory)
+        ));
+    }
+
+    #[test]
+    fn test_hybrid_agrees() {
+        let initiator = HybridInitiator::new();
+        let (reply, server) = hybrid_respond(initiator.message()).unwrap();
+        let client = initiator.finish(&reply).unwrap();
+
+        assert_eq!(client.chaining_key, server.chaining_key);
+        assert_eq!(client.handshake_hash, server.handshake_hash);
+    }
+
+    #[test]
+    fn test_hybrid_rejects_malformed_messages() {
+        let initiator = HybridInitiator::new();
+        assert!(matches!(
+            hybrid_respond(&initiator.message()[..100]),
+            Err(HandshakeError::InvalidKemMessage(100))
+        ));
+
+        let (reply, _) = hybrid_respond(initiator.message()).unwrap();
+        assert!(matches!(
+            initiator.finish(&reply[..32]),
+            Err(HandshakeError::InvalidKemMessage(32))
+        ));
+    }
+
+    #[test]
+    fn test_ik_with_wrong_server_key_fails() {
+        let client_static = StaticSecret::random_from_rng(OsRng);
+        let server_static = StaticSecret::random_from_rng(OsRng);
+        let impostor = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+
+        let mut initiator =
+            HandshakeState::initiator(Pattern::IK, client_static, Some(impostor)).unwrap();
+        let mut responder = HandshakeState::responder(Pattern::IK, server_static);
+
+        let message = initiator.write_message(&[]).unwrap();
+        assert!(matches!(
+            responder.read_message(&message),
+            Err(HandshakeError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn test_resumption_needs_the_ticket_secret() {
+        let (client, _) = run(Pattern::XX, false);
+        let resumption = Resumption::of(&client.parameter_key(), &client.chaining_key);
+        let ticket_key = TicketKey::generate(crate::ticket::DEFAULT_LIFETIME);
+        let ticket = ticket_key.issue(&resumption);
+
+        let redeemed = ticket_key.redeem(&ticket).unwrap();
+        let server = resumed_outcome(&redeemed, &ticket, &[1; 32], &[2; 32]);
+        let resumed = resumed_outcome(&resumption, &ticket, &[1; 32], &[2; 32]);
+        assert_eq!(resumed.chaining_key, server.chaining_key);
+        assert_ne!(resumed.chaining_key, client.chaining_key);
+        assert_eq!(resumed.parameter_key(), client.chaining_key);
+        let tag = confirmation_tag(&resumed, Role::Client);
+        assert!(verify_confirmation(&server, Role::Client, &tag).is_ok());
+
+        // someone who only captured the ticket can't finish the handshake
+        let thief = Resumption {
+            secret: [0; 32],
+            ..resumption
+        };
+        let stolen = resumed_outcome(&thief, &ticket, &[1; 32], &[2; 32]);
+        let tag = confirmation_tag(&stolen, Role::Client);
+        assert!(matches!(
+            verify_confirmation(&server, Role::Client, &tag),
+            Err(HandshakeError::KeyConfirmationFailed)
+        ));
+    }
+}
@@ -0,0 +1,151 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{common, stream::KeyStream};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Number of sites, all stepped together every iteration
+pub const LANES: usize = 8;
+/// Bytes of key stream taken from each site per iteration
+const BYTES_PER_LANE: usize = 4;
+/// Iterations thrown away so the seed has spread through the whole ring
+const WARM_UP: usize = 32;
+
+/// A ring of logistic maps, each pulled towards its neighbours with strength `epsilon`.
+/// One iteration yields `LANES * 4` key bytes, where a Lorenz step yields a single `f64`
+/// whose sign and exponent barely change, so it is the generator for bulk messages.
+/// The sites are plain arrays stepped in lockstep, which the compiler vectorises
+pub struct CoupledMapLattice {
+    sites: [f64; LANES],
+    r: f64,
+    epsilon: f64,
+    // key bytes of the last iteration that were not needed yet
+    pending: Vec<u8>,
+}
+
+impl CoupledMapLattice {
+    pub const EPSILON: f64 = 0.2;
+
+    /// Seeds the sites from a state both sides agreed on, such as the quantized
+    /// synced state, so that neither ever sends the lattice's starting point
+    pub fn from_state(secret: &[u8; 32], state: &[f64]) -> CoupledMapLattice {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+        mac.update(b"lattice seed");
+        state
+            .iter()
+            .for_each(|value| mac.update(&value.to_le_bytes()));
+        let seed = mac.finalize().into_bytes();
+
+        let mut sites = [0.0; LANES];
+        for (site, bytes) in sites.iter_mut().zip(seed.chunks(4)) {
+            let word = u32::from_le_bytes(bytes.try_into().unwrap());
+            let unit = (word as f64 + 0.5) / (u32::MAX as f64 + 1.0);
+            // clear of 0 and 1, where the logistic map gets stuck
+            *site = 0.01 + 0.98 * unit;
+        }
+
+        let mut lattice = CoupledMapLattice {
+            sites,
+            r: common::lin_interp(secret[10] as f64, 0.0, 3.999, 255.0, 4.0),
+            epsilon: CoupledMapLattice::EPSILON,
+            pending: Vec::new(),
+        };
+        for _ in 0..WARM_UP {
+            lattice.step();
+        }
+        lattice
+    }
+
+    pub fn sites(&self) -> &[f64; LANES] {
+        &self.sites
+    }
+
+    pub fn step(&mut self) {
+        let mut mapped = self.sites;
+        mapped
+            .iter_mut()
+            .for_each(|x| *x = self.r * *x * (1.0 - *x));
+
+        let mut left = [0.0; LANES];
+        let mut right = [0.0; LANES];
+        left[1..].copy_from_slice(&mapped[..LANES - 1]);
+        left[0] = mapped[LANES - 1];
+        right[..LANES - 1].copy_from_slice(&mapped[1..]);
+        right[LANES - 1] = mapped[0];
+
+        for i in 0..LANES {
+            self.sites[i] =
+                (1.0 - self.epsilon) * mapped[i] + 0.5 * self.epsilon * (left[i] + right[i]);
+        }
+    }
+
+    /// The next `len` bytes, taken from the middle of each site's mantissa: the
+    /// top bits follow the map too closely and the bottom ones carry rounding noise
+    pub fn key_stream(&mut self, len: usize) -> Vec<u8> {
+        while self.pending.len() < len {
+            self.step();
+            for site in self.sites {
+                let bits = (site.to_bits() >> 16) as u32;
+                self.pending
+                    .extend_from_slice(&bits.to_le_bytes()[..BYTES_PER_LANE]);
+            }
+        }
+        self.pending.drain(..len).collect()
+    }
+}
+
+impl KeyStream for CoupledMapLattice {
+    fn fill(&mut self, key: &mut [u8]) {
+        key.copy_from_slice(&self.key_stream(key.len()));
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_same_state_gives_the_same_key_stream() {
+        let state = [-6.100626, -1.896558, 30.187181];
+
+        let mut first = CoupledMapLattice::from_state(&[3; 32], &state);
+        let mut second = CoupledMapLattice::from_state(&[3; 32], &state);
+        assert_eq!(first.key_stream(4096), second.key_stream(4096));
+
+        let mut other_state =
+            CoupledMapLattice::from_state(&[3; 32], &[-6.100626, -1.896558, 30.187182]);
+        let mut other_secret = CoupledMapLattice::from_state(&[4; 32], &state);
+        let key_stream = first.key_stream(64);
+        assert_ne!(other_state.key_stream(64), key_stream);
+        assert_ne!(other_secret.key_stream(64), key_stream);
+    }
+
+    #[test]
+    fn test_sites_stay_in_the_unit_interval() {
+        let mut lattice = CoupledMapLattice::from_state(&[255; 32], &[0.5]);
+        for _ in 0..100_000 {
+            lattice.step();
+            assert!(lattice.sites().iter().all(|&x| x > 0.0 && x < 1.0));
+        }
+    }
+
+    #[test]
+    fn test_key_stream_bytes_are_evenly_spread() {
+        let mut lattice = CoupledMapLattice::from_state(&[7; 32], &[1.0, 2.0, 3.0]);
+        let key_stream = lattice.key_stream(1 << 18);
+
+        let mut counts = [0usize; 256];
+        key_stream
+            .iter()
+            .for_each(|&byte| counts[byte as usize] += 1);
+
+        // chi-squared with 255 degrees of freedom, far below the 1e-6 tail
+        let expected = key_stream.len() as f64 / 256.0;
+        let chi_squared: f64 = counts
+            .iter()
+            .map(|&count| (count as f64 - expected).powi(2) / expected)
+            .sum();
+        assert!(chi_squared < 400.0, "{}", chi_squared);
+    }
+}
@@ -1,6 +1,24 @@
+pub mod attractor;
+pub mod chunk;
+pub mod container;
+pub mod envelope;
+pub mod handshake;
+pub mod lattice;
+pub mod maps;
+pub mod masking;
+pub mod nonce;
+pub mod parallel;
+pub mod pipeline;
+pub mod rekey;
+pub mod scheme;
+pub mod stream;
+pub mod sync;
+pub mod ticket;
+
 pub mod common {
 
     use tungstenite::{util::NonBlockingError, Message, WebSocket};
+    #[allow(clippy::too_many_arguments)]
     pub fn lorenz_attractor(
         x: f64,
         x_prime: Option<f64>,
@@ -19,34 +37,51 @@ pub mod common {
         (new_x, new_y, new_z)
     }
 
-    pub fn send_request<S>(socket: &mut WebSocket<S>, name: &str, request_id: u8) -> ()
+    pub fn send_request<S>(socket: &mut WebSocket<S>, name: &str, request_id: u8)
     where
         S: std::io::Read + std::io::Write,
     {
         socket
             .send(Message::Binary(vec![request_id]))
-            .expect(format!("Unable to send request: {}", name).as_str());
+            .unwrap_or_else(|_| panic!("Unable to send request: {}", name));
 
         println!("Sent: {}", name);
     }
 
-    pub fn receive_msg<S>(socket: &mut WebSocket<S>) -> ()
+    pub fn receive_msg<S>(socket: &mut WebSocket<S>)
     where
         S: std::io::Read + std::io::Write,
     {
-        let msg = socket.read().expect("Error reading message");
+        let msg = read_data(socket).expect("Error reading message");
         println!("Recieved: {}", msg);
     }
 
-    pub fn read_non_blocking<S>(socket: &mut WebSocket<S>) -> Option<Message>
+    /// Reads the next message that isn't a ping or a pong. Pings are answered by the
+    /// socket itself while reading
+    #[allow(clippy::result_large_err)]
+    pub fn read_data<S>(socket: &mut WebSocket<S>) -> tungstenite::Result<Message>
+    where
+        S: std::io::Read + std::io::Write,
+    {
+        loop {
+            match socket.read()? {
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+                msg => return Ok(msg),
+            }
+        }
+    }
+
+    /// `None` when nothing has arrived yet, an error once the connection is gone
+    #[allow(clippy::result_large_err)]
+    pub fn read_non_blocking<S>(socket: &mut WebSocket<S>) -> tungstenite::Result<Option<Message>>
     where
         S: std::io::Read + std::io::Write,
     {
         match socket.read() {
-            Ok(msg) => Some(msg),
+            Ok(msg) => Ok(Some(msg)),
             Err(err) => match err.into_non_blocking() {
-                Some(e) => panic!("Panic at message {}", e),
-                None => None,
+                Some(e) => Err(e),
+                None => Ok(None),
             },
         }
     }
@@ -55,6 +90,14 @@ pub mod common {
         y1 + ((y2 - y1) / (x2 - x1)) * (input - x1)
     }
 
+    /// Maps the agreed secret onto the system parameters (rho, sigma)
+    pub fn derive_parameters(secret: &[u8; 32]) -> (f64, f64) {
+        let rho = lin_interp(secret[10] as f64, 0.0, 24.0, 255.0, 57.0);
+        let sigma = interpolate_sigma(rho);
+
+        (rho, sigma)
+    }
+
     pub fn interpolate_sigma(rho: f64) -> f64 {
         let rho_range = [24.0, 57.0];
         let sigma_range_for_24 = [6.0, 14.5];
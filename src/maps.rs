@@ -0,0 +1,179 @@
+use crate::attractor::Attractor;
+
+/// The logistic map `x' = r x (1 - x)`, chaotic on (0, 1) for `r` just below 4
+pub struct Logistic {
+    pub r: f64,
+}
+
+impl Attractor for Logistic {
+    fn dimension(&self) -> usize {
+        1
+    }
+
+    // away from the fixed points at 0 and 1 - 1/r
+    fn initial_state(&self) -> Vec<f64> {
+        vec![0.3]
+    }
+
+    fn step(&self, state: &[f64]) -> Vec<f64> {
+        vec![self.r * state[0] * (1.0 - state[0])]
+    }
+
+    fn step_size(&self) -> f64 {
+        1.0
+    }
+
+    fn key_coordinates(&self) -> &'static [usize] {
+        &[0]
+    }
+}
+
+/// The tent map. `mu` has to stay below 2: at 2 every step shifts a bit out of the
+/// mantissa and the orbit collapses onto 0 within 60 steps
+pub struct Tent {
+    pub mu: f64,
+}
+
+impl Attractor for Tent {
+    fn dimension(&self) -> usize {
+        1
+    }
+
+    fn initial_state(&self) -> Vec<f64> {
+        vec![0.3]
+    }
+
+    fn step(&self, state: &[f64]) -> Vec<f64> {
+        let x = state[0];
+        vec![self.mu * x.min(1.0 - x)]
+    }
+
+    fn step_size(&self) -> f64 {
+        1.0
+    }
+
+    fn key_coordinates(&self) -> &'static [usize] {
+        &[0]
+    }
+}
+
+/// The Hénon map `x' = 1 - a x² + y, y' = b x`. Driving `x` syncs a receiver in two steps
+pub struct Henon {
+    pub a: f64,
+    pub b: f64,
+}
+
+impl Attractor for Henon {
+    fn dimension(&self) -> usize {
+        2
+    }
+
+    // inside the basin of the attractor; far from it the orbit escapes to infinity
+    fn initial_state(&self) -> Vec<f64> {
+        vec![0.1, 0.1]
+    }
+
+    fn step(&self, state: &[f64]) -> Vec<f64> {
+        let (x, y) = (state[0], state[1]);
+        vec![1.0 - self.a * x * x + y, self.b * x]
+    }
+
+    fn step_size(&self) -> f64 {
+        1.0
+    }
+
+    fn key_coordinates(&self) -> &'static [usize] {
+        &[1]
+    }
+}
+
+/// The generalised Arnold cat map on the unit torus,
+/// `x' = x + p y, y' = q x + (pq + 1) y (mod 1)`. It stretches any error in `y`,
+/// driven or not, so it has no receiver and only generates key streams
+pub struct ArnoldCat {
+    pub p: f64,
+    pub q: f64,
+}
+
+impl Attractor for ArnoldCat {
+    fn dimension(&self) -> usize {
+        2
+    }
+
+    fn initial_state(&self) -> Vec<f64> {
+        vec![0.3, 0.7]
+    }
+
+    fn step(&self, state: &[f64]) -> Vec<f64> {
+        let (x, y) = (state[0], state[1]);
+        vec![
+            (x + self.p * y).rem_euclid(1.0),
+            (self.q * x + (self.p * self.q + 1.0) * y).rem_euclid(1.0),
+        ]
+    }
+
+    fn step_size(&self) -> f64 {
+        1.0
+    }
+
+    fn key_coordinates(&self) -> &'static [usize] {
+        &[0, 1]
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use crate::attractor::{driven_step, lyapunov_spectrum, AttractorKind};
+
+    #[test]
+    fn test_every_secret_gives_a_chaotic_map() {
+        for kind in [
+            AttractorKind::Logistic,
+            AttractorKind::Tent,
+            AttractorKind::Henon,
+        ] {
+            for secret_byte in 0..=255u8 {
+                let map = kind.build(&[secret_byte; 32]);
+                let mut state = map.initial_state();
+                for _ in 0..1_000 {
+                    state = map.step(&state);
+                }
+
+                let spectrum = lyapunov_spectrum(map.as_ref(), &state, 5_000);
+                assert!(
+                    spectrum[0] > 0.05,
+                    "{}, secret byte = {}: {:?}",
+                    kind,
+                    secret_byte,
+                    spectrum
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_tent_map_does_not_collapse() {
+        let map = AttractorKind::Tent.build(&[255; 32]);
+        let mut state = map.initial_state();
+        for _ in 0..100_000 {
+            state = map.step(&state);
+            assert!(state[0] > 0.0 && state[0] < 1.0, "{:?}", state);
+        }
+    }
+
+    #[test]
+    fn test_arnold_cat_stays_on_the_torus_and_cannot_be_driven() {
+        let map = AttractorKind::ArnoldCat.build(&[0; 32]);
+        let mut driver = map.initial_state();
+        let mut receiver = vec![0.3, 0.7 + 1e-9];
+
+        // not much longer: the doubled error runs out of mantissa bits after ~50 steps
+        for _ in 0..40 {
+            receiver = driven_step(map.as_ref(), &receiver, driver[0]);
+            driver = map.step(&driver);
+            assert!(driver.iter().all(|value| (0.0..1.0).contains(value)));
+        }
+
+        assert!((receiver[1] - driver[1]).abs() > 1e-3);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::attractor::{self, Attractor};
+
+/// Scale of one message byte on the drive signal
+pub const AMPLITUDE: f64 = 1.0 / 256.0;
+
+/// Chaotic masking: each byte rides on the drive signal instead of being XORed with
+/// a key stream. The sender's system is driven by the masked signal too, so a receiver
+/// fed the same signal follows the same trajectory, subtracts it back out, and syncs
+/// itself if it starts slightly off.
+///
+/// That self-sync is also why the signal hides nothing by itself: a receiver only
+/// needs the attractor's parameters, which come from a single byte of the secret,
+/// so an eavesdropper can try all of them. Send it encrypted and authenticated
+pub fn mask(message: &[u8], attractor: &dyn Attractor, state: &[f64]) -> Vec<f64> {
+    let mut state = state.to_vec();
+
+    message
+        .iter()
+        .map(|&byte| {
+            let drive = state[0] + byte as f64 * AMPLITUDE;
+            state = attractor::driven_step(attractor, &state, drive);
+            drive
+        })
+        .collect()
+}
+
+pub fn unmask(signal: &[f64], attractor: &dyn Attractor, state: &[f64]) -> Vec<u8> {
+    let mut state = state.to_vec();
+
+    signal
+        .iter()
+        .map(|&drive| {
+            let byte = ((drive - state[0]) / AMPLITUDE).round().clamp(0.0, 255.0) as u8;
+            state = attractor::driven_step(attractor, &state, drive);
+            byte
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::attractor::AttractorKind;
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn test_unmask_recovers_the_message() {
+        for kind in [AttractorKind::Lorenz, AttractorKind::HyperLorenz] {
+            let attractor = kind.build(&[3; 32]);
+            let state = attractor.initial_state();
+            let message = message(4096);
+
+            let signal = mask(&message, attractor.as_ref(), &state);
+            assert_eq!(
+                unmask(&signal, attractor.as_ref(), &state),
+                message,
+                "{}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn test_signal_hides_the_message() {
+        let attractor = AttractorKind::Lorenz.build(&[3; 32]);
+        let state = vec![-6.100626, -1.896558, 30.187181];
+
+        // a constant message still goes out as a chaotic signal
+        let signal = mask(&[b'a'; 64], attractor.as_ref(), &state);
+        let spread = signal.iter().cloned().fold(f64::MIN, f64::max)
+            - signal.iter().cloned().fold(f64::MAX, f64::min);
+        assert!(spread > 1.0);
+    }
+
+    #[test]
+    fn test_receiver_syncs_itself_to_the_signal() {
+        let attractor = AttractorKind::Lorenz.build(&[3; 32]);
+        let state = vec![-6.100626, -1.896558, 30.187181];
+        let message = message(8192);
+
+        let signal = mask(&message, attractor.as_ref(), &state);
+        let off: Vec<f64> = state.iter().map(|value| value + 0.5).collect();
+        let recovered = unmask(&signal, attractor.as_ref(), &off);
+
+        assert_ne!(recovered[..16], message[..16]);
+        assert_eq!(recovered[4096..], message[4096..]);
+    }
+
+    #[test]
+    fn test_wrong_parameters_garble_the_message() {
+        let attractor = AttractorKind::Lorenz.build(&[3; 32]);
+        let other = AttractorKind::Lorenz.build(&[200; 32]);
+        let state = vec![-6.100626, -1.896558, 30.187181];
+        let message = message(4096);
+
+        let signal = mask(&message, attractor.as_ref(), &state);
+        let recovered = unmask(&signal, other.as_ref(), &state);
+
+        let matching = recovered
+            .iter()
+            .zip(message.iter())
+            .filter(|(a, b)| a == b)
+            .count();
+        assert!(matching < message.len() / 4);
+    }
+}
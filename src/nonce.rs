@@ -0,0 +1,152 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::attractor::Attractor;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_LEN: usize = 16;
+pub type Nonce = [u8; NONCE_LEN];
+
+/// Time units the nudged state runs before its key stream is used, enough for a
+/// nudge of a millionth to grow to the size of the attractor
+const WARM_UP_TIME: f64 = 40.0;
+
+/// A fresh nonce for every message. With 16 random bytes two messages sharing one
+/// is out of the question, and with `mask` over their key streams neither do those
+pub fn generate() -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Where a message's key stream starts: the synced state shrunk on every coordinate
+/// by a sub-quantum factor drawn from the secret and the nonce, then run until the
+/// nudge has spread. Shrinking rather than shifting keeps the maps on (0, 1).
+/// Only about 32 bits of the nonce fit in each coordinate, so a one-dimensional
+/// map lands on a start state it has used before after some 2^16 messages
+pub fn initial_state(
+    attractor: &dyn Attractor,
+    secret: &[u8; 32],
+    state: &[f64],
+    nonce: &Nonce,
+) -> Vec<f64> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(b"message nonce");
+    mac.update(nonce);
+    let seed = mac.finalize().into_bytes();
+
+    let mut state: Vec<f64> = state
+        .iter()
+        .zip(seed.chunks(4).cycle())
+        .map(|(value, bytes)| {
+            let word = u32::from_le_bytes(bytes.try_into().unwrap());
+            value * (1.0 - 1e-6 * (word as f64 + 1.0) / (u32::MAX as f64 + 1.0))
+        })
+        .collect();
+
+    let warm_up = (WARM_UP_TIME / attractor.step_size()).ceil() as usize;
+    for _ in 0..warm_up {
+        state = attractor.step(&state);
+    }
+    state
+}
+
+/// XORs a message's key stream, from its first byte, with blocks of HMAC over the
+/// secret and the whole nonce. Two messages whose nonces led to the same start
+/// state still get different key streams
+pub fn mask(secret: &[u8; 32], nonce: &Nonce, key_stream: &mut [u8]) {
+    for (block, bytes) in key_stream.chunks_mut(32).enumerate() {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+        mac.update(b"message mask");
+        mac.update(nonce);
+        mac.update(&(block as u64).to_le_bytes());
+        bytes
+            .iter_mut()
+            .zip(mac.finalize().into_bytes())
+            .for_each(|(byte, mask)| *byte ^= mask);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::{
+        attractor::AttractorKind,
+        stream::{AttractorKeyStream, KeyStream},
+    };
+
+    const STATE: [f64; 3] = [-6.100626, -1.896558, 30.187181];
+
+    fn key_stream(attractor: &dyn Attractor, state: &[f64]) -> [u8; 64] {
+        let mut key = [0; 64];
+        AttractorKeyStream::new(attractor, state).fill(&mut key);
+        key
+    }
+
+    #[test]
+    fn test_nonce_changes_the_key_stream() {
+        let attractor = AttractorKind::Lorenz.build(&[5; 32]);
+        let attractor = attractor.as_ref();
+
+        let first = initial_state(attractor, &[5; 32], &STATE, &[1; NONCE_LEN]);
+        let again = initial_state(attractor, &[5; 32], &STATE, &[1; NONCE_LEN]);
+        let second = initial_state(attractor, &[5; 32], &STATE, &[2; NONCE_LEN]);
+
+        assert_eq!(key_stream(attractor, &first), key_stream(attractor, &again));
+        assert_ne!(
+            key_stream(attractor, &first),
+            key_stream(attractor, &second)
+        );
+        assert_ne!(key_stream(attractor, &first), key_stream(attractor, &STATE));
+        // the nudge has grown to the size of the attractor
+        assert!(
+            (first[1] - second[1]).abs() > 1e-3,
+            "{:?} {:?}",
+            first,
+            second
+        );
+    }
+
+    #[test]
+    fn test_masked_key_streams_differ_when_start_states_collide() {
+        for kind in [AttractorKind::Logistic, AttractorKind::Tent] {
+            let attractor = kind.build(&[5; 32]);
+            let attractor = attractor.as_ref();
+            // as if two nonces had led to the same start state
+            let start = initial_state(attractor, &[5; 32], &[0.4], &[1; NONCE_LEN]);
+            let mut first = key_stream(attractor, &start);
+            let mut second = first;
+
+            mask(&[5; 32], &[1; NONCE_LEN], &mut first);
+            mask(&[5; 32], &[2; NONCE_LEN], &mut second);
+            assert_ne!(first[..32], second[..32], "{}", kind);
+            assert_ne!(first[32..], second[32..], "{}", kind);
+
+            // masking is its own inverse, so the receiver takes it off the same way
+            mask(&[5; 32], &[1; NONCE_LEN], &mut first);
+            assert_eq!(first, key_stream(attractor, &start), "{}", kind);
+        }
+
+        let mut split = [0; 70];
+        mask(&[5; 32], &[1; NONCE_LEN], &mut split);
+        let mut whole = [0; 100];
+        mask(&[5; 32], &[1; NONCE_LEN], &mut whole);
+        assert_eq!(split, whole[..70]);
+    }
+
+    #[test]
+    fn test_generated_nonces_differ() {
+        assert_ne!(generate(), generate());
+    }
+
+    #[test]
+    fn test_maps_stay_in_their_domain() {
+        for kind in [AttractorKind::Logistic, AttractorKind::Tent] {
+            let attractor = kind.build(&[255; 32]);
+            let state = initial_state(attractor.as_ref(), &[255; 32], &[1.0], &generate());
+            assert!((0.0..=1.0).contains(&state[0]), "{}", kind);
+        }
+    }
+}
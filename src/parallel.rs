@@ -0,0 +1,213 @@
+use hmac::{Hmac, Mac};
+use rayon::prelude::*;
+use sha2::Sha256;
+
+use crate::{
+    attractor::Attractor,
+    stream::{AttractorKeyStream, KeyStream},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes each trajectory contributes before the next one takes over
+pub const BLOCK: usize = 64;
+/// Time units every trajectory runs on its own before it is used, enough to settle
+/// back onto the attractor from wherever its seed put it
+const WARM_UP_TIME: f64 = 40.0;
+/// The most a seed shrinks a coordinate by. Anything much smaller leaves the low
+/// bits of the seed below the coordinate's precision
+const SPREAD: f64 = 0.5;
+
+/// Runs several trajectories of the same attractor side by side, one per thread,
+/// and deals their key bytes out in blocks of `BLOCK`: block `j` of the stream
+/// comes from trajectory `j % K`. The stream is the same however it is split into
+/// calls, so both sides only have to agree on the seed and `K`
+pub struct ParallelKeyStream<'a> {
+    trajectories: Vec<AttractorKeyStream<'a>>,
+    position: usize,
+}
+
+impl<'a> ParallelKeyStream<'a> {
+    /// Each trajectory starts from `state` shrunk by a different factor drawn from
+    /// `secret`. Shrinking rather than shifting keeps the maps on (0, 1)
+    pub fn new(
+        attractor: &'a dyn Attractor,
+        secret: &[u8; 32],
+        state: &[f64],
+        trajectories: usize,
+    ) -> ParallelKeyStream<'a> {
+        assert!(trajectories > 0, "At least one trajectory is needed");
+        let warm_up = (WARM_UP_TIME / attractor.step_size()).ceil() as usize;
+
+        let trajectories = (0..trajectories)
+            .into_par_iter()
+            .map(|k| {
+                let mut state = seed(secret, k, state);
+                for _ in 0..warm_up {
+                    state = attractor.step(&state);
+                }
+
+                AttractorKeyStream::new(attractor, &state)
+            })
+            .collect();
+
+        ParallelKeyStream {
+            trajectories,
+            position: 0,
+        }
+    }
+
+    pub fn trajectories(&self) -> usize {
+        self.trajectories.len()
+    }
+
+    // how many of the first `end` bytes of the stream come from trajectory `k`
+    fn share(&self, k: usize, end: usize) -> usize {
+        let round = self.trajectories.len() * BLOCK;
+        let partial = (end % round).saturating_sub(k * BLOCK).min(BLOCK);
+        end / round * BLOCK + partial
+    }
+
+    pub fn key_stream(&mut self, len: usize) -> Vec<u8> {
+        let (start, end) = (self.position, self.position + len);
+        let needed: Vec<usize> = (0..self.trajectories.len())
+            .map(|k| self.share(k, end) - self.share(k, start))
+            .collect();
+
+        let parts: Vec<Vec<u8>> = self
+            .trajectories
+            .par_iter_mut()
+            .zip(needed)
+            .map(|(trajectory, needed)| {
+                let mut part = vec![0; needed];
+                trajectory.fill(&mut part);
+                part
+            })
+            .collect();
+
+        let mut offsets = vec![0; parts.len()];
+        let mut key_stream = Vec::with_capacity(len);
+        let mut position = start;
+        while position < end {
+            let k = position / BLOCK % parts.len();
+            let run = (BLOCK - position % BLOCK).min(end - position);
+            key_stream.extend_from_slice(&parts[k][offsets[k]..offsets[k] + run]);
+            offsets[k] += run;
+            position += run;
+        }
+
+        self.position = end;
+        key_stream
+    }
+}
+
+// Shrinks every coordinate by a factor in (1 - SPREAD, 1] taken from 53 bits of an
+// HMAC, so every bit of the result's mantissa depends on the secret
+fn seed(secret: &[u8; 32], k: usize, state: &[f64]) -> Vec<f64> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(b"trajectory seed");
+    mac.update(&(k as u64).to_le_bytes());
+    state
+        .iter()
+        .for_each(|value| mac.update(&value.to_le_bytes()));
+    let seed = mac.finalize().into_bytes();
+
+    state
+        .iter()
+        .zip(seed.chunks(8).cycle())
+        .map(|(value, bytes)| {
+            let word = u64::from_le_bytes(bytes.try_into().unwrap()) >> 11;
+            value * (1.0 - SPREAD * word as f64 / (1u64 << 53) as f64)
+        })
+        .collect()
+}
+
+impl KeyStream for ParallelKeyStream<'_> {
+    fn fill(&mut self, key: &mut [u8]) {
+        key.copy_from_slice(&self.key_stream(key.len()));
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::attractor::{self, AttractorKind};
+
+    const STATE: [f64; 3] = [-6.100626, -1.896558, 30.187181];
+
+    #[test]
+    fn test_key_stream_does_not_depend_on_how_it_is_split() {
+        let attractor = AttractorKind::Lorenz.build(&[5; 32]);
+
+        let whole =
+            ParallelKeyStream::new(attractor.as_ref(), &[5; 32], &STATE, 4).key_stream(5000);
+
+        let mut pieces = ParallelKeyStream::new(attractor.as_ref(), &[5; 32], &STATE, 4);
+        let mut split = Vec::new();
+        for len in [1, 63, 64, 65, 200, 7, 4600] {
+            split.extend(pieces.key_stream(len));
+        }
+
+        assert_eq!(split, whole);
+    }
+
+    #[test]
+    fn test_one_trajectory_follows_its_own_state() {
+        let attractor = AttractorKind::Lorenz.build(&[5; 32]);
+        let mut stream = ParallelKeyStream::new(attractor.as_ref(), &[5; 32], &STATE, 1);
+        let key_stream = stream.key_stream(1024);
+
+        let mut state = stream.trajectories[0].state().to_vec();
+        let mut expected = Vec::new();
+        while expected.len() < 64 {
+            state = attractor.step(&state);
+            expected.extend(attractor::key_bytes(attractor.as_ref(), &state));
+        }
+        assert_eq!(stream.key_stream(64), expected);
+        assert_eq!(key_stream.len(), 1024);
+    }
+
+    #[test]
+    fn test_trajectories_are_independent() {
+        let attractor = AttractorKind::Lorenz.build(&[5; 32]);
+        let mut stream = ParallelKeyStream::new(attractor.as_ref(), &[5; 32], &STATE, 4);
+        let key_stream = stream.key_stream(BLOCK * 4);
+
+        let blocks: Vec<&[u8]> = key_stream.chunks(BLOCK).collect();
+        for (i, a) in blocks.iter().enumerate() {
+            for b in &blocks[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+
+        let states: Vec<&[f64]> = stream.trajectories.iter().map(|t| t.state()).collect();
+        assert!((states[0][1] - states[1][1]).abs() > 1e-3, "{:?}", states);
+    }
+
+    #[test]
+    fn test_seeds_spread_over_the_whole_range() {
+        let seeds: Vec<f64> = (0..256).map(|k| seed(&[9; 32], k, &[1.0])[0]).collect();
+
+        assert!(seeds.iter().all(|s| (1.0 - SPREAD..=1.0).contains(s)));
+        assert!(seeds.iter().any(|s| *s < 1.0 - SPREAD * 0.9));
+        assert!(seeds.iter().any(|s| *s > 1.0 - SPREAD * 0.1));
+        // the low bits differ too, not only the leading ones
+        let low_bits: std::collections::HashSet<u64> =
+            seeds.iter().map(|s| s.to_bits() & 0xffff).collect();
+        assert!(low_bits.len() > 250);
+        assert_ne!(seed(&[9; 32], 0, &[1.0]), seed(&[8; 32], 0, &[1.0]));
+    }
+
+    #[test]
+    fn test_maps_stay_in_their_domain() {
+        for kind in [AttractorKind::Logistic, AttractorKind::Tent] {
+            let attractor = kind.build(&[255; 32]);
+            let mut stream = ParallelKeyStream::new(attractor.as_ref(), &[255; 32], &[1.0], 8);
+            stream.key_stream(4096);
+
+            for trajectory in &stream.trajectories {
+                assert!((0.0..=1.0).contains(&trajectory.state()[0]), "{}", kind);
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::VecDeque, fmt};
+
+use tungstenite::{Message, WebSocket};
+
+use crate::envelope::EnvelopeError;
+
+pub const DELIVERED: u8 = 17;
+pub const REFUSED: u8 = 18;
+
+/// Why the server couldn't deliver a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefusalCode {
+    /// The server couldn't line its key stream up with the message's, because the
+    /// offset is out of range or nothing was synced to start it from
+    Alignment = 1,
+    /// The envelope isn't from this session or was changed on the way
+    Mac = 2,
+    /// The envelope or the plaintext in it couldn't be read
+    Decode = 3,
+    /// Delivered before, or too old to tell
+    Replay = 4,
+}
+
+impl RefusalCode {
+    pub fn from_byte(byte: u8) -> Option<RefusalCode> {
+        match byte {
+            1 => Some(RefusalCode::Alignment),
+            2 => Some(RefusalCode::Mac),
+            3 => Some(RefusalCode::Decode),
+            4 => Some(RefusalCode::Replay),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RefusalCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefusalCode::Alignment => write!(f, "the key streams couldn't be aligned"),
+            RefusalCode::Mac => write!(f, "the MAC didn't check out"),
+            RefusalCode::Decode => write!(f, "the message couldn't be decoded"),
+            RefusalCode::Replay => write!(f, "the message was replayed"),
+        }
+    }
+}
+
+impl From<&EnvelopeError> for RefusalCode {
+    fn from(e: &EnvelopeError) -> RefusalCode {
+        match e {
+            EnvelopeError::Malformed
+            | EnvelopeError::UnsupportedVersion(_)
+            | EnvelopeError::LengthMismatch { .. } => RefusalCode::Decode,
+            EnvelopeError::WrongSession | EnvelopeError::BadMac => RefusalCode::Mac,
+            EnvelopeError::Replayed(_) | EnvelopeError::Stale(_) => RefusalCode::Replay,
+        }
+    }
+}
+
+/// The server's answer to one envelope, sent in the order the envelopes came in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Receipt {
+    Delivered(u64),
+    Refused { id: u64, code: RefusalCode },
+}
+
+impl Receipt {
+    pub fn id(&self) -> u64 {
+        match self {
+            Receipt::Delivered(id) | Receipt::Refused { id, .. } => *id,
+        }
+    }
+
+    // [DELIVERED] [id: u64]
+    // [REFUSED] [id: u64] [code]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Receipt::Delivered(id) => [&[DELIVERED], &id.to_le_bytes()[..]].concat(),
+            Receipt::Refused { id, code } => {
+                [&[REFUSED], &id.to_le_bytes()[..], &[*code as u8]].concat()
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Receipt> {
+        let id = u64::from_le_bytes(bytes.get(1..9)?.try_into().unwrap());
+        match bytes {
+            [DELIVERED, _, _, _, _, _, _, _, _] => Some(Receipt::Delivered(id)),
+            [REFUSED, _, _, _, _, _, _, _, _, code] => Some(Receipt::Refused {
+                id,
+                code: RefusalCode::from_byte(*code)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Receipt::Delivered(id) => write!(f, "message {} was delivered", id),
+            Receipt::Refused { id, code } => write!(f, "message {} was refused: {}", id, code),
+        }
+    }
+}
+
+/// Messages sent and not answered yet, oldest first. The client keeps sending until
+/// `depth` of them are waiting on a receipt
+pub struct InFlight {
+    depth: usize,
+    ids: VecDeque<u64>,
+}
+
+impl InFlight {
+    pub fn new(depth: usize) -> InFlight {
+        InFlight {
+            depth,
+            ids: VecDeque::with_capacity(depth),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.ids.len() >= self.depth
+    }
+
+    pub fn sent(&mut self, id: u64) {
+        self.ids.push_back(id);
+    }
+
+    /// Takes the oldest message off, as long as the receipt is for it. The server
+    /// answers in order, so any other receipt means the two sides lost track
+    pub fn settle(&mut self, receipt: &Receipt) -> bool {
+        if self.ids.front() != Some(&receipt.id()) {
+            return false;
+        }
+        self.ids.pop_front();
+        true
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// The connection closed or failed before the receipt came
+    ConnectionLost,
+    Malformed,
+    /// A receipt for a message other than the oldest one in flight
+    Unexpected(u64),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::ConnectionLost => write!(f, "the server stopped answering"),
+            DeliveryError::Malformed => write!(f, "malformed receipt"),
+            DeliveryError::Unexpected(id) => {
+                write!(f, "unexpected receipt for message {}", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// Waits for the receipt of the oldest message in flight and settles it
+pub fn await_receipt<S>(
+    socket: &mut WebSocket<S>,
+    in_flight: &mut InFlight,
+) -> Result<Receipt, DeliveryError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let receipt = match crate::common::read_data(socket) {
+        Ok(Message::Binary(v)) => Receipt::from_bytes(&v).ok_or(DeliveryError::Malformed)?,
+        Ok(Message::Close(_)) | Err(_) => return Err(DeliveryError::ConnectionLost),
+        Ok(_) => return Err(DeliveryError::Malformed),
+    };
+    if !in_flight.settle(&receipt) {
+        return Err(DeliveryError::Unexpected(receipt.id()));
+    }
+    Ok(receipt)
+}
+
+/// Waits until no more than `keep` messages are in flight, returning what became of
+/// the ones that landed
+pub fn await_receipts<S>(
+    socket: &mut WebSocket<S>,
+    in_flight: &mut InFlight,
+    keep: usize,
+) -> Result<Vec<Receipt>, DeliveryError>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let mut receipts = Vec::new();
+    while in_flight.len() > keep {
+        receipts.push(await_receipt(socket, in_flight)?);
+    }
+    Ok(receipts)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_receipt_round_trip() {
+        let receipts = [
+            Receipt::Delivered(7),
+            Receipt::Refused {
+                id: u64::MAX,
+                code: RefusalCode::Replay,
+            },
+            Receipt::Refused {
+                id: 0,
+                code: RefusalCode::Alignment,
+            },
+        ];
+        for receipt in receipts {
+            assert_eq!(Receipt::from_bytes(&receipt.to_bytes()), Some(receipt));
+        }
+
+        assert_eq!(Receipt::from_bytes(&[DELIVERED, 1, 2]), None);
+        assert_eq!(Receipt::from_bytes(&[DELIVERED; 10]), None);
+        assert_eq!(Receipt::from_bytes(&[REFUSED; 9]), None);
+        assert_eq!(Receipt::from_bytes(&[REFUSED; 10]), None);
+        assert_eq!(Receipt::from_bytes(&[3; 9]), None);
+    }
+
+    #[test]
+    fn test_envelope_errors_have_codes() {
+        let cases = [
+            (EnvelopeError::Malformed, RefusalCode::Decode),
+            (EnvelopeError::UnsupportedVersion(9), RefusalCode::Decode),
+            (EnvelopeError::BadMac, RefusalCode::Mac),
+            (EnvelopeError::WrongSession, RefusalCode::Mac),
+            (EnvelopeError::Replayed(3), RefusalCode::Replay),
+            (EnvelopeError::Stale(3), RefusalCode::Replay),
+        ];
+        for (error, code) in cases {
+            assert_eq!(RefusalCode::from(&error), code);
+            assert_eq!(RefusalCode::from_byte(code as u8), Some(code));
+        }
+    }
+
+    #[test]
+    fn test_receipts_settle_in_order() {
+        let mut in_flight = InFlight::new(2);
+        in_flight.sent(4);
+        assert!(!in_flight.is_full());
+        in_flight.sent(5);
+        assert!(in_flight.is_full());
+
+        assert!(!in_flight.settle(&Receipt::Delivered(5)));
+        assert!(in_flight.settle(&Receipt::Delivered(4)));
+        assert!(in_flight.settle(&Receipt::Refused {
+            id: 5,
+            code: RefusalCode::Mac,
+        }));
+        assert!(in_flight.is_empty());
+        assert!(!in_flight.settle(&Receipt::Delivered(6)));
+    }
+}
@@ -0,0 +1,238 @@
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    attractor::Attractor,
+    nonce::{self, NONCE_LEN},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const REKEY: u8 = 16;
+// [REKEY] [epoch: u32] [mac; 32]
+pub const REKEY_LEN: usize = 1 + 4 + 32;
+
+fn mac(key: &[u8; 32], data: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    data.iter().for_each(|d| mac.update(d));
+    mac
+}
+
+fn hmac(key: &[u8; 32], data: &[&[u8]]) -> [u8; 32] {
+    mac(key, data).finalize().into_bytes().into()
+}
+
+/// When the client moves the session on to its next keys. Whichever limit is
+/// reached first triggers it, `None` never does
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    pub max_messages: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_messages: Some(1000),
+            max_bytes: Some(1 << 20),
+            max_age: Some(Duration::from_secs(10 * 60)),
+        }
+    }
+}
+
+/// Counts what has been sent under the current keys
+pub struct RekeySchedule {
+    policy: RekeyPolicy,
+    messages: u64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl RekeySchedule {
+    pub fn new(policy: RekeyPolicy) -> RekeySchedule {
+        RekeySchedule {
+            policy,
+            messages: 0,
+            bytes: 0,
+            since: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+
+    pub fn due(&self) -> bool {
+        self.policy
+            .max_messages
+            .is_some_and(|max| self.messages >= max)
+            || self.policy.max_bytes.is_some_and(|max| self.bytes >= max)
+            || self
+                .policy
+                .max_age
+                .is_some_and(|max| self.since.elapsed() >= max)
+    }
+
+    pub fn reset(&mut self) {
+        *self = RekeySchedule::new(self.policy);
+    }
+}
+
+/// The secrets a session's current epoch runs on. Every epoch's come from the last
+/// one's through a one-way ratchet that overwrites them, so keys recovered from a
+/// session say nothing of the messages sent before its last rekey
+pub struct SessionKeys {
+    pub epoch: u32,
+    pub parameter_key: [u8; 32],
+    pub sync_key: [u8; 32],
+    secret: [u8; 32],
+}
+
+impl SessionKeys {
+    pub fn new(parameter_key: &[u8; 32], sync_key: &[u8; 32]) -> SessionKeys {
+        SessionKeys {
+            epoch: 0,
+            parameter_key: *parameter_key,
+            sync_key: *sync_key,
+            secret: hmac(sync_key, &[b"rekey secret"]),
+        }
+    }
+
+    pub fn ratchet(&mut self) {
+        self.secret = hmac(&self.secret, &[b"ratchet"]);
+        self.parameter_key = hmac(&self.secret, &[b"parameters"]);
+        self.sync_key = hmac(&self.secret, &[b"sync"]);
+        self.epoch += 1;
+    }
+
+    /// Where the client's trajectory restarts in the new epoch, drawn from its keys
+    pub fn initial_state(&self, attractor: &dyn Attractor) -> Vec<f64> {
+        let mut salt = [0; NONCE_LEN];
+        salt[..4].copy_from_slice(&self.epoch.to_le_bytes());
+        nonce::initial_state(
+            attractor,
+            &hmac(&self.secret, &[b"initial conditions"]),
+            &attractor.initial_state(),
+            &salt,
+        )
+    }
+
+    /// Asks for the switch to `epoch`, under the keys being left
+    pub fn request(&self) -> Vec<u8> {
+        self.signal(self.epoch + 1, b"rekey request")
+    }
+
+    /// Acknowledges the switch, under the keys switched to
+    pub fn acknowledgement(&self) -> Vec<u8> {
+        self.signal(self.epoch, b"rekey acknowledgement")
+    }
+
+    pub fn verify_request(&self, signal: &[u8]) -> bool {
+        self.verify(signal, self.epoch + 1, b"rekey request")
+    }
+
+    pub fn verify_acknowledgement(&self, signal: &[u8]) -> bool {
+        self.verify(signal, self.epoch, b"rekey acknowledgement")
+    }
+
+    fn signal(&self, epoch: u32, label: &[u8]) -> Vec<u8> {
+        let epoch = epoch.to_le_bytes();
+        let mut signal = vec![REKEY];
+        signal.extend_from_slice(&epoch);
+        signal.extend_from_slice(&hmac(&self.sync_key, &[label, &epoch]));
+        signal
+    }
+
+    fn verify(&self, signal: &[u8], epoch: u32, label: &[u8]) -> bool {
+        let epoch = epoch.to_le_bytes();
+        signal.len() == REKEY_LEN
+            && signal[0] == REKEY
+            && signal[1..5] == epoch
+            && mac(&self.sync_key, &[label, &epoch])
+                .verify_slice(&signal[5..])
+                .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::attractor::AttractorKind;
+
+    #[test]
+    fn test_both_sides_ratchet_to_the_same_keys() {
+        let mut client = SessionKeys::new(&[1; 32], &[2; 32]);
+        let mut server = SessionKeys::new(&[1; 32], &[2; 32]);
+
+        let request = client.request();
+        assert_eq!(request.len(), REKEY_LEN);
+        assert!(server.verify_request(&request));
+        server.ratchet();
+        let acknowledgement = server.acknowledgement();
+        // the acknowledgement only checks out once the client has moved on too
+        assert!(!client.verify_acknowledgement(&acknowledgement));
+        client.ratchet();
+        assert!(client.verify_acknowledgement(&acknowledgement));
+
+        assert_eq!(client.epoch, 1);
+        assert_eq!(client.parameter_key, server.parameter_key);
+        assert_eq!(client.sync_key, server.sync_key);
+        assert_ne!(client.parameter_key, [1; 32]);
+        assert_ne!(client.sync_key, [2; 32]);
+
+        let attractor = AttractorKind::Lorenz.build(&client.parameter_key);
+        let state = client.initial_state(attractor.as_ref());
+        assert_eq!(state, server.initial_state(attractor.as_ref()));
+        client.ratchet();
+        assert_ne!(state, client.initial_state(attractor.as_ref()));
+    }
+
+    #[test]
+    fn test_stale_and_forged_requests_are_refused() {
+        let mut keys = SessionKeys::new(&[1; 32], &[2; 32]);
+        let stale = keys.request();
+        keys.ratchet();
+        assert!(!keys.verify_request(&stale));
+
+        let mut forged = keys.request();
+        forged[REKEY_LEN - 1] ^= 1;
+        assert!(!keys.verify_request(&forged));
+        assert!(!keys.verify_request(&keys.acknowledgement()));
+    }
+
+    #[test]
+    fn test_schedule_triggers_on_any_limit() {
+        let mut messages = RekeySchedule::new(RekeyPolicy {
+            max_messages: Some(2),
+            max_bytes: None,
+            max_age: None,
+        });
+        messages.record(10);
+        assert!(!messages.due());
+        messages.record(10);
+        assert!(messages.due());
+        messages.reset();
+        assert!(!messages.due());
+
+        let mut bytes = RekeySchedule::new(RekeyPolicy {
+            max_messages: None,
+            max_bytes: Some(100),
+            max_age: None,
+        });
+        bytes.record(99);
+        assert!(!bytes.due());
+        bytes.record(1);
+        assert!(bytes.due());
+
+        let age = RekeySchedule::new(RekeyPolicy {
+            max_messages: None,
+            max_bytes: None,
+            max_age: Some(Duration::ZERO),
+        });
+        assert!(age.due());
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+
+use crate::attractor::{self, Attractor};
+
+/// How the receiver is coupled to the drive signal (the driver's first coordinate).
+/// Every scheme lags the driver by one step: fed the driver's `x` at step k, a synced
+/// receiver lands on the driver's state at step k + 1
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncScheme {
+    /// Pecora-Carroll: the receiver's `x` is replaced by the drive outright
+    Replacement,
+    /// The receiver runs freely and is pulled towards the drive with gain `k`
+    Diffusive { k: f64 },
+    /// The drive feeds the nonlinear terms and the receiver keeps its own `x`
+    ActivePassive,
+    /// Active-passive plus output injection of the drive error, one gain per
+    /// coordinate (missing gains are 0)
+    Observer { gain: Vec<f64> },
+}
+
+impl SyncScheme {
+    pub const DEFAULT_DIFFUSIVE_GAIN: f64 = 50.0;
+    pub const DEFAULT_OBSERVER_GAIN: [f64; 3] = [10.0, 5.0, 0.0];
+
+    pub fn step(&self, attractor: &dyn Attractor, receiver: &[f64], drive: f64) -> Vec<f64> {
+        match self {
+            SyncScheme::Replacement => attractor::driven_step(attractor, receiver, drive),
+            SyncScheme::Diffusive { k } => {
+                let mut next = attractor.step(receiver);
+                next[0] += k * (drive - receiver[0]) * attractor.step_size();
+                next
+            }
+            SyncScheme::ActivePassive => active_passive(attractor, receiver, drive),
+            SyncScheme::Observer { gain } => {
+                let innovation = drive - receiver[0];
+                let mut next = active_passive(attractor, receiver, drive);
+                next.iter_mut()
+                    .zip(gain.iter())
+                    .for_each(|(value, l)| *value += l * innovation * attractor.step_size());
+                next
+            }
+        }
+    }
+
+    /// Accepts `replacement`, `diffusive[:k]`, `active-passive` and `observer[:l1,l2,...]`
+    pub fn from_name(name: &str) -> Option<SyncScheme> {
+        let (name, gains) = match name.split_once(':') {
+            Some((name, gains)) => (name, Some(gains)),
+            None => (name, None),
+        };
+        let gains = match gains {
+            Some(gains) => Some(
+                gains
+                    .split(',')
+                    .map(|gain| gain.trim().parse::<f64>().ok().filter(|g| g.is_finite()))
+                    .collect::<Option<Vec<f64>>>()?,
+            ),
+            None => None,
+        };
+
+        match (name, gains.as_deref()) {
+            ("replacement", None) => Some(SyncScheme::Replacement),
+            ("diffusive", None) => Some(SyncScheme::Diffusive {
+                k: SyncScheme::DEFAULT_DIFFUSIVE_GAIN,
+            }),
+            ("diffusive", Some(&[k])) => Some(SyncScheme::Diffusive { k }),
+            ("active-passive", None) => Some(SyncScheme::ActivePassive),
+            ("observer", None) => Some(SyncScheme::Observer {
+                gain: SyncScheme::DEFAULT_OBSERVER_GAIN.to_vec(),
+            }),
+            ("observer", Some(gain)) => Some(SyncScheme::Observer {
+                gain: gain.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    // [id] [gains as f64 LE]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            SyncScheme::Replacement => vec![0],
+            SyncScheme::Diffusive { k } => {
+                let mut bytes = vec![1];
+                bytes.extend_from_slice(&k.to_le_bytes());
+                bytes
+            }
+            SyncScheme::ActivePassive => vec![2],
+            SyncScheme::Observer { gain } => {
+                let mut bytes = vec![3];
+                gain.iter()
+                    .for_each(|l| bytes.extend_from_slice(&l.to_le_bytes()));
+                bytes
+            }
+        }
+    }
+
+    /// An empty slice is the Pecora-Carroll replacement every client used to get
+    pub fn from_bytes(bytes: &[u8]) -> Option<SyncScheme> {
+        let gains: Vec<f64> = bytes
+            .get(1..)
+            .unwrap_or_default()
+            .chunks(8)
+            .map(|chunk| chunk.try_into().ok().map(f64::from_le_bytes))
+            .collect::<Option<_>>()?;
+        if gains.iter().any(|gain| !gain.is_finite()) {
+            return None;
+        }
+
+        match (bytes.first(), gains.as_slice()) {
+            (None, []) | (Some(0), []) => Some(SyncScheme::Replacement),
+            (Some(1), &[k]) => Some(SyncScheme::Diffusive { k }),
+            (Some(2), []) => Some(SyncScheme::ActivePassive),
+            (Some(3), gain) if !gain.is_empty() => Some(SyncScheme::Observer {
+                gain: gain.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn active_passive(attractor: &dyn Attractor, receiver: &[f64], drive: f64) -> Vec<f64> {
+    let mut next = attractor::driven_step(attractor, receiver, drive);
+    next[0] = attractor.step(receiver)[0];
+    next
+}
+
+impl fmt::Display for SyncScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncScheme::Replacement => write!(f, "replacement"),
+            SyncScheme::Diffusive { k } => write!(f, "diffusive (k = {})", k),
+            SyncScheme::ActivePassive => write!(f, "active-passive"),
+            SyncScheme::Observer { gain } => {
+                let gain: Vec<String> = gain.iter().map(|l| l.to_string()).collect();
+                write!(f, "observer (l = {})", gain.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::{
+        attractor::AttractorKind,
+        sync::{quantize_state, SyncConfig, SyncDetector, SyncStatus},
+    };
+
+    fn schemes() -> Vec<SyncScheme> {
+        vec![
+            SyncScheme::Replacement,
+            SyncScheme::Diffusive {
+                k: SyncScheme::DEFAULT_DIFFUSIVE_GAIN,
+            },
+            SyncScheme::ActivePassive,
+            SyncScheme::Observer {
+                gain: SyncScheme::DEFAULT_OBSERVER_GAIN.to_vec(),
+            },
+        ]
+    }
+
+    // drives a receiver until the detector decides, returning the status, the
+    // receiver, the driver one step behind it and how many steps it took
+    fn drive(
+        attractor: &dyn Attractor,
+        scheme: &SyncScheme,
+    ) -> (SyncStatus, Vec<f64>, Vec<f64>, usize) {
+        let mut driver = attractor.initial_state();
+        let mut receiver = vec![1.0; attractor.dimension()];
+        let mut detector = SyncDetector::new(SyncConfig::default());
+
+        loop {
+            driver = attractor.step(&driver);
+            let error = (receiver[0] - driver[0]).abs();
+            receiver = scheme.step(attractor, &receiver, driver[0]);
+
+            match detector.observe(error, driver[0].abs()) {
+                SyncStatus::Converging => (),
+                status => return (status, receiver, driver, detector.steps()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_scheme_syncs_every_attractor() {
+        for kind in [AttractorKind::Lorenz, AttractorKind::HyperLorenz] {
+            for scheme in schemes() {
+                for secret_byte in [0u8, 90, 180, 255] {
+                    let attractor = kind.build(&[secret_byte; 32]);
+                    let attractor = attractor.as_ref();
+                    let (status, receiver, driver, _) = drive(attractor, &scheme);
+                    assert_eq!(
+                        status,
+                        SyncStatus::Synced,
+                        "{}, {}, secret byte = {}",
+                        kind,
+                        scheme,
+                        secret_byte
+                    );
+
+                    let quantum = SyncConfig::default().quantum;
+                    assert_eq!(
+                        quantize_state(&receiver, quantum),
+                        quantize_state(&attractor.step(&driver), quantum),
+                        "{}, {}, secret byte = {}",
+                        kind,
+                        scheme,
+                        secret_byte
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_replacement_syncs_the_discrete_maps_at_once() {
+        let window = SyncConfig::default().window;
+
+        for kind in [
+            AttractorKind::Logistic,
+            AttractorKind::Tent,
+            AttractorKind::Henon,
+        ] {
+            assert!(kind.syncs_with(&SyncScheme::Replacement));
+            for secret_byte in [0u8, 90, 180, 255] {
+                let attractor = kind.build(&[secret_byte; 32]);
+                let (status, receiver, driver, steps) =
+                    drive(attractor.as_ref(), &SyncScheme::Replacement);
+
+                assert_eq!(status, SyncStatus::Synced, "{}", kind);
+                assert!(steps <= window + 2, "{}: {} steps", kind, steps);
+                assert_eq!(receiver, attractor.step(&driver), "{}", kind);
+            }
+        }
+
+        for scheme in schemes().into_iter().skip(1) {
+            assert!(!AttractorKind::Henon.syncs_with(&scheme));
+        }
+        assert!(!AttractorKind::ArnoldCat.syncs_with(&SyncScheme::Replacement));
+    }
+
+    #[test]
+    fn test_weak_diffusive_coupling_does_not_sync() {
+        let attractor = AttractorKind::Lorenz.build(&[0; 32]);
+        let attractor = attractor.as_ref();
+        let scheme = SyncScheme::Diffusive { k: 0.0 };
+        let mut driver = attractor.initial_state();
+        let mut receiver = vec![1.0, 1.0, 2.0];
+
+        for _ in 0..20_000 {
+            driver = attractor.step(&driver);
+            receiver = scheme.step(attractor, &receiver, driver[0]);
+        }
+
+        assert!((receiver[0] - driver[0]).abs() > 1e-3);
+    }
+
+    #[test]
+    fn test_scheme_encoding() {
+        for scheme in schemes() {
+            assert_eq!(SyncScheme::from_bytes(&scheme.to_bytes()), Some(scheme));
+        }
+
+        assert_eq!(SyncScheme::from_bytes(&[]), Some(SyncScheme::Replacement));
+        assert_eq!(SyncScheme::from_bytes(&[1]), None);
+        assert_eq!(SyncScheme::from_bytes(&[1, 0, 0]), None);
+        assert_eq!(SyncScheme::from_bytes(&[3]), None);
+        assert_eq!(SyncScheme::from_bytes(&[9]), None);
+
+        let mut nan = vec![1];
+        nan.extend_from_slice(&f64::NAN.to_le_bytes());
+        assert_eq!(SyncScheme::from_bytes(&nan), None);
+    }
+
+    #[test]
+    fn test_scheme_from_name() {
+        assert_eq!(
+            SyncScheme::from_name("replacement"),
+            Some(SyncScheme::Replacement)
+        );
+        assert_eq!(
+            SyncScheme::from_name("diffusive:20"),
+            Some(SyncScheme::Diffusive { k: 20.0 })
+        );
+        assert_eq!(
+            SyncScheme::from_name("observer:1,2,3"),
+            Some(SyncScheme::Observer {
+                gain: vec![1.0, 2.0, 3.0]
+            })
+        );
+        assert_eq!(
+            SyncScheme::from_name("observer:1,2,3,4"),
+            Some(SyncScheme::Observer {
+                gain: vec![1.0, 2.0, 3.0, 4.0]
+            })
+        );
+        assert_eq!(
+            SyncScheme::from_name("active-passive"),
+            Some(SyncScheme::ActivePassive)
+        );
+        assert_eq!(SyncScheme::from_name("diffusive:1,2"), None);
+        assert_eq!(SyncScheme::from_name("diffusive:fast"), None);
+        assert_eq!(SyncScheme::from_name("replacement:1"), None);
+        assert_eq!(SyncScheme::from_name("magic"), None);
+    }
+}
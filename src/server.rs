@@ -1,47 +1,86 @@
-use std::{net::TcpListener, thread::spawn, time::SystemTime};
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{sleep, spawn, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
 
 use tungstenite::{
     accept_hdr,
-    handshake::server::{Request, Response},
-    Message,
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    util::NonBlockingError,
+    Message, WebSocket,
 };
 
 use rand::rngs::OsRng;
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use x25519_dalek::{PublicKey, StaticSecret};
 
 use base64::prelude::*;
-use strange_cipher::common;
+use strange_cipher::{
+    attractor::AttractorKind,
+    chunk::{self, Chunk, ChunkedHeader, Reassembly},
+    common,
+    envelope::{ContentType, Encoding, Envelope, EnvelopeError, ReplayWindow},
+    handshake, masking,
+    nonce::{self, Nonce},
+    pipeline::{Receipt, RefusalCode},
+    rekey::{self, SessionKeys},
+    scheme::SyncScheme,
+    stream::{AttractorKeyStream, BulkKeyStream, KeyStream},
+    sync::{self, DriveBatch, SyncConfig, SyncDetector, SyncStatus},
+    ticket::{self, Resumption, TicketKey},
+};
 
 enum ServerState {
     Unverified,
-    Unsynced {
-        rho: f64,
-        sigma: f64,
-    },
+    Unsynced,
     Syncing {
-        rho: f64,
-        sigma: f64,
-    },
-    Synced {
-        rho: f64,
-        sigma: f64,
+        scheme: SyncScheme,
     },
+    Synced,
     Encrypted {
-        rho: f64,
-        sigma: f64,
+        encoding: Encoding,
+        /// The message ID the client put in the request, so a receipt can name it
+        /// even when the envelope won't open
+        id: u64,
+    },
+    Unmasking {
+        encoding: Encoding,
+        id: u64,
+    },
+    BulkDecrypting {
+        key_stream: BulkKeyStream,
+        nonce: Nonce,
+        id: u64,
+    },
+    Receiving {
+        reassembly: Reassembly,
     },
     Decrypted {
-        rho: f64,
-        sigma: f64,
         plaintext: String,
     },
 }
 
-fn decrypt(base64_message: &str, key_stream: &[u8]) -> Vec<u8> {
-    let encrypted_message_bytes = BASE64_STANDARD.decode(base64_message).unwrap();
+/// How far into its key stream an envelope's key may start. Anything further is
+/// refused rather than stepped towards
+const MAX_KEY_STREAM_OFFSET: usize = 1 << 16;
+/// How long a blocking read waits before the session checks whether it should end
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a session may take over the message it is on once the server is
+/// shutting down
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn decrypt(envelope: &Envelope, key_stream: &[u8]) -> Vec<u8> {
     let mut decrypted_message = Vec::new();
 
-    for (i, &byte) in encrypted_message_bytes.iter().enumerate() {
+    for (i, &byte) in envelope.ciphertext.iter().enumerate() {
         let key_byte = key_stream[i % key_stream.len()];
         let encrypted_byte = byte ^ key_byte;
         decrypted_message.push(encrypted_byte);
@@ -50,14 +89,439 @@ fn decrypt(base64_message: &str, key_stream: &[u8]) -> Vec<u8> {
     decrypted_message
 }
 
+// Sends a message, or logs why it couldn't. `false` once the client is gone. On a
+// non-blocking socket, a message that can't go out at once is queued, not lost
+#[must_use]
+fn send<S>(websocket: &mut WebSocket<S>, client: usize, msg: Message) -> bool
+where
+    S: std::io::Read + std::io::Write,
+{
+    match websocket.send(msg).map_err(|e| e.into_non_blocking()) {
+        Ok(()) | Err(None) => true,
+        Err(Some(e)) => {
+            println!("Lost the connection to Client {}: {}", client, e);
+            false
+        }
+    }
+}
+
+#[must_use]
+fn send_receipt<S>(websocket: &mut WebSocket<S>, client: usize, receipt: Receipt) -> bool
+where
+    S: std::io::Read + std::io::Write,
+{
+    if !send(websocket, client, Message::Binary(receipt.to_bytes())) {
+        return false;
+    }
+    match receipt {
+        Receipt::Delivered(id) => println!("Sent: Delivered message {}", id),
+        Receipt::Refused { id, code } => println!("Sent: Refused message {} ({})", id, code),
+    }
+    true
+}
+
+// The log gets the whole reason, the client only its code
+#[must_use]
+fn refuse<S>(
+    websocket: &mut WebSocket<S>,
+    client: usize,
+    id: u64,
+    code: RefusalCode,
+    reason: impl fmt::Display,
+) -> bool
+where
+    S: std::io::Read + std::io::Write,
+{
+    println!("Rejected message {} from client {}: {}", id, client, reason);
+    send_receipt(websocket, client, Receipt::Refused { id, code })
+}
+
+// [3] [encoding] [message id: u64]
+fn parse_encrypted_request(request: &[u8]) -> Option<(Encoding, u64)> {
+    match request {
+        [3, encoding, id @ ..] if id.len() == 8 => Some((
+            Encoding::from_byte(*encoding)?,
+            u64::from_le_bytes(id.try_into().unwrap()),
+        )),
+        _ => None,
+    }
+}
+
+// [7] [envelope encoding] [message id: u64]
+fn parse_masked_request(request: &[u8]) -> Option<(Encoding, u64)> {
+    match request {
+        [7, encoding, id @ ..] if id.len() == 8 => Some((
+            Encoding::from_byte(*encoding)?,
+            u64::from_le_bytes(id.try_into().unwrap()),
+        )),
+        _ => None,
+    }
+}
+
+// [8] [message id: u64] [nonce; 16]
+// [9] [message id: u64] [trajectories] [nonce; 16]
+fn parse_bulk_request(request: &[u8]) -> Option<(u64, BulkKeyStream, Nonce)> {
+    let (&kind, rest) = request.split_first()?;
+    let id = u64::from_le_bytes(rest.get(..8)?.try_into().unwrap());
+    let (key_stream, nonce) = match (kind, &rest[8..]) {
+        (8, nonce) => (BulkKeyStream::Lattice, nonce),
+        (9, [trajectories, nonce @ ..]) if *trajectories > 0 => (
+            BulkKeyStream::Parallel {
+                trajectories: *trajectories,
+            },
+            nonce,
+        ),
+        _ => return None,
+    };
+    Some((id, key_stream, nonce.try_into().ok()?))
+}
+
+// Answers a decrypted message with its receipt, and refuses it if it isn't text.
+// `None` once the client is gone
+fn deliver<S>(
+    websocket: &mut WebSocket<S>,
+    client: usize,
+    id: u64,
+    decoded_message: Vec<u8>,
+) -> Option<ServerState>
+where
+    S: std::io::Read + std::io::Write,
+{
+    match String::from_utf8(decoded_message) {
+        Ok(plaintext) => send_receipt(websocket, client, Receipt::Delivered(id))
+            .then_some(ServerState::Decrypted { plaintext }),
+        Err(_) => {
+            let reason = "the plaintext isn't valid UTF-8";
+            refuse(websocket, client, id, RefusalCode::Decode, reason)
+                .then_some(ServerState::Unsynced)
+        }
+    }
+}
+
+// The request names the attractor and the coupling scheme the client wants for this
+// sync. An empty one is what clients sent before either could be chosen. `None`
+// once the session is over, because the request made no sense or the client is gone
+fn approve_sync_request(
+    websocket: &mut WebSocket<TcpStream>,
+    client: usize,
+    request: &[u8],
+) -> Option<(AttractorKind, SyncScheme)> {
+    let requested = match request {
+        [] => Some((AttractorKind::Lorenz, SyncScheme::Replacement)),
+        [kind, scheme @ ..] => AttractorKind::from_byte(*kind).zip(SyncScheme::from_bytes(scheme)),
+    };
+    let (kind, scheme) = match requested {
+        Some((kind, scheme)) if kind.syncs_with(&scheme) => (kind, scheme),
+        Some((kind, scheme)) => {
+            let reason = format!("{} can't be synced with {}", kind, scheme);
+            protocol_error(websocket, client, &reason);
+            return None;
+        }
+        None => {
+            protocol_error(websocket, client, "invalid sync request");
+            return None;
+        }
+    };
+    println!("Received: Sync Request ({}, {})", kind, scheme);
+    if !send(
+        websocket,
+        client,
+        Message::Text("Sync Request approved".to_string()),
+    ) {
+        return None;
+    }
+
+    println!("Sent: Sync Request approved");
+
+    Some((kind, scheme))
+}
+
+struct Options {
+    sync: SyncConfig,
+    ticket_lifetime: Duration,
+    /// How long a session may go between messages before it is closed
+    idle_timeout: Duration,
+    /// How often an idle session is pinged, to notice clients that went away
+    ping_interval: Duration,
+    max_connections: usize,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        sync: SyncConfig::default(),
+        ticket_lifetime: ticket::DEFAULT_LIFETIME,
+        idle_timeout: Duration::from_secs(5 * 60),
+        ping_interval: Duration::from_secs(30),
+        max_connections: 64,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or_else(|| panic!("{} needs a number", arg))
+        };
+        match arg.as_str() {
+            "--sync-max-steps" => options.sync.max_steps = value() as usize,
+            "--sync-timeout-ms" => options.sync.timeout = Duration::from_millis(value()),
+            "--ticket-lifetime-secs" => options.ticket_lifetime = Duration::from_secs(value()),
+            "--idle-timeout-secs" => match value() {
+                0 => panic!("--idle-timeout-secs needs a positive number"),
+                secs => options.idle_timeout = Duration::from_secs(secs),
+            },
+            "--ping-interval-secs" => options.ping_interval = Duration::from_secs(value()),
+            "--max-connections" => options.max_connections = value() as usize,
+            _ => panic!("Unknown argument: {}", arg),
+        }
+    }
+
+    options
+}
+
+/// Counts a session as open for as long as its thread holds on to it, panics included
+struct OpenSession(Arc<AtomicUsize>);
+
+impl Drop for OpenSession {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Answers the upgrade request with a 503, so the client learns why
+fn turn_away(stream: TcpStream, open: usize) {
+    #[allow(clippy::result_large_err)]
+    let callback = |_: &Request, _: Response| {
+        let mut response = ErrorResponse::new(Some(format!(
+            "The server is full, {} sessions are open",
+            open
+        )));
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        Err(response)
+    };
+    let _ = accept_hdr(stream, callback);
+}
+
+/// What came of reading the envelope a request announced
+enum Opened {
+    Envelope(Envelope),
+    /// The client has been sent a refusal, and the session goes on
+    Refused,
+    /// The session is over
+    Closed,
+}
+
+// Reads the envelope of message `id` and refuses it unless it opens under `secret`
+// with a counter the session hasn't delivered yet
+fn open_envelope(
+    websocket: &mut WebSocket<TcpStream>,
+    client: usize,
+    deadline: &mut Deadline,
+    encoding: Encoding,
+    id: u64,
+    secret: &[u8; 32],
+    replay_window: &mut ReplayWindow,
+) -> Opened {
+    let refused = |sent: bool| {
+        if sent {
+            Opened::Refused
+        } else {
+            Opened::Closed
+        }
+    };
+
+    let frame = match read_frame(websocket, client, deadline) {
+        Some(Message::Text(text)) => text.into_bytes(),
+        Some(Message::Binary(bytes)) => bytes,
+        Some(_) => {
+            let reason = "the envelope isn't a data frame";
+            return refused(refuse(websocket, client, id, RefusalCode::Decode, reason));
+        }
+        None => return Opened::Closed,
+    };
+
+    let envelope = match encoding.open(&frame, secret) {
+        Ok(envelope) => envelope,
+        Err(e) => return refused(refuse(websocket, client, id, RefusalCode::from(&e), e)),
+    };
+    // only once the MAC vouches for the counter
+    if let Err(e) = replay_window.accept(envelope.counter) {
+        if let EnvelopeError::Replayed(_) = e {
+            println!("Replay detected from client {}: {}", client, e)
+        }
+        return refused(refuse(websocket, client, id, RefusalCode::from(&e), e));
+    }
+    Opened::Envelope(envelope)
+}
+
+/// Why a session stops waiting on its client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expiry {
+    ShuttingDown,
+    Idle,
+}
+
+impl Expiry {
+    fn end(self, websocket: &mut WebSocket<TcpStream>, client: usize) {
+        let (log, reason) = match self {
+            Expiry::ShuttingDown => ("shutting down", "the server is shutting down"),
+            Expiry::Idle => ("idle", "idle timeout"),
+        };
+        println!("Closing the session with Client {}: {}", client, log);
+        close(websocket, CloseCode::Away, reason);
+    }
+}
+
+/// When a session in the middle of a message gives up on its client: once it has
+/// waited `idle_timeout` for one frame, or `DRAIN_TIMEOUT` after it noticed the
+/// server shutting down
+struct Deadline {
+    shutdown: Arc<AtomicBool>,
+    idle_timeout: Duration,
+    draining_since: Option<Instant>,
+}
+
+impl Deadline {
+    fn check(&mut self, waiting_since: Instant) -> Option<Expiry> {
+        if self.shutdown.load(Ordering::SeqCst) {
+            let draining_since = *self.draining_since.get_or_insert_with(Instant::now);
+            if draining_since.elapsed() >= DRAIN_TIMEOUT {
+                return Some(Expiry::ShuttingDown);
+            }
+        }
+        (waiting_since.elapsed() >= self.idle_timeout).then_some(Expiry::Idle)
+    }
+}
+
+// The next message that isn't a ping or a pong. `None` once the session is over:
+// the client said goodbye or just dropped, or the deadline ran out. Reads time out
+// every `POLL_INTERVAL`, so the deadline is looked at while the client is quiet too
+fn read_frame(
+    websocket: &mut WebSocket<TcpStream>,
+    client: usize,
+    deadline: &mut Deadline,
+) -> Option<Message> {
+    let waiting_since = Instant::now();
+    loop {
+        // a client that keeps talking doesn't hold up a shutdown either
+        if let Some(expiry) = deadline.check(waiting_since) {
+            expiry.end(websocket, client);
+            return None;
+        }
+        match common::read_data(websocket).map_err(|e| e.into_non_blocking()) {
+            Ok(Message::Close(_)) => {
+                println!("Client {} closed the connection", client);
+                return None;
+            }
+            Ok(msg) => return Some(msg),
+            Err(None) => continue,
+            Err(Some(e)) => {
+                println!("Lost the connection to Client {}: {}", client, e);
+                return None;
+            }
+        }
+    }
+}
+
+// Switches between polling for the next message and blocking reads in the middle
+// of one. `false` once the socket is unusable
+#[must_use]
+fn set_nonblocking(websocket: &mut WebSocket<TcpStream>, client: usize, nonblocking: bool) -> bool {
+    match websocket.get_mut().set_nonblocking(nonblocking) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Lost the connection to Client {}: {}", client, e);
+            false
+        }
+    }
+}
+
+// Ends the session over something no client of ours would send
+fn protocol_error(websocket: &mut WebSocket<TcpStream>, client: usize, reason: &str) {
+    println!("Closing the session with Client {}: {}", client, reason);
+    close(websocket, CloseCode::Protocol, reason);
+}
+
+fn close(websocket: &mut WebSocket<TcpStream>, code: CloseCode, reason: &str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    };
+    // the close frame has to be out before the socket is dropped
+    let _ = websocket.get_mut().set_nonblocking(false);
+    let _ = websocket.close(Some(frame));
+    let _ = websocket.flush();
+}
+
 fn main() {
     env_logger::init();
 
+    let options = Arc::new(parse_args());
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))
+            .expect("Couldn't listen for signals");
+    }
+
     let server = TcpListener::bind("127.0.0.1:3012").unwrap();
+    // polled, so a signal is noticed between connections
+    server
+        .set_nonblocking(true)
+        .expect("Couldn't make the listener non-blocking");
     println!("Server Started");
 
-    for (i, stream) in server.incoming().enumerate() {
-        spawn(move || {
+    let static_key = StaticSecret::random_from_rng(OsRng);
+    println!(
+        "Server static key: {}",
+        BASE64_STANDARD.encode(PublicKey::from(&static_key).as_bytes())
+    );
+    let ticket_key = TicketKey::generate(options.ticket_lifetime);
+
+    let open_sessions = Arc::new(AtomicUsize::new(0));
+    let mut sessions = Vec::new();
+    let mut connections = 0;
+    while !shutdown.load(Ordering::SeqCst) {
+        let stream = match server.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(50));
+                continue;
+            }
+            Err(e) => {
+                println!("Couldn't accept a connection: {}", e);
+                continue;
+            }
+        };
+        let i = connections;
+        connections += 1;
+        // a peer stuck in the upgrade or the key exchange holds up a shutdown for no
+        // longer than one in the middle of a message
+        if let Err(e) = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(options.idle_timeout.min(DRAIN_TIMEOUT))))
+        {
+            println!("Couldn't set up the connection with Client {}: {}", i, e);
+            continue;
+        }
+
+        let open = open_sessions.load(Ordering::SeqCst);
+        if open >= options.max_connections {
+            println!("Turned Client {} away, {} sessions are open", i, open);
+            spawn(move || turn_away(stream, open));
+            continue;
+        }
+        open_sessions.fetch_add(1, Ordering::SeqCst);
+        let session = OpenSession(Arc::clone(&open_sessions));
+
+        let static_key = static_key.clone();
+        let ticket_key = ticket_key.clone();
+        let options = Arc::clone(&options);
+        let shutdown = Arc::clone(&shutdown);
+        sessions.retain(|session: &JoinHandle<()>| !session.is_finished());
+        sessions.push(spawn(move || {
+            let _session = session;
+            #[allow(clippy::result_large_err)]
             let callback = |req: &Request, response: Response| {
                 println!("New Client connected");
                 println!("The request's path is: {}", req.uri().path());
@@ -73,17 +537,37 @@ fn main() {
 
                 Ok(response)
             };
-            let mut websocket = accept_hdr(stream.unwrap(), callback).unwrap();
+            let mut websocket = match accept_hdr(stream, callback) {
+                Ok(websocket) => websocket,
+                Err(e) => {
+                    println!("Couldn't open a session with Client {}: {}", i, e);
+                    return;
+                }
+            };
+            let mut last_heard = Instant::now();
+            let mut last_ping = Instant::now();
+            let mut deadline = Deadline {
+                shutdown: Arc::clone(&shutdown),
+                idle_timeout: options.idle_timeout,
+                draining_since: None,
+            };
 
             let mut stream_state = ServerState::Unverified;
 
-            let mut seed = (1.0, 1.0, 2.0);
-            let beta = 8.0 / 3.0;
-            let h = 0.01;
-            let mut last_y = 0.;
-            let mut last_z = 0.;
-            let mut sync_count = 0;
-            let mut key_stream = Vec::new();
+            let mut sync_key = [0; 32];
+            // a resumed session keeps the attractor parameters of the one it resumes
+            let mut parameter_key = sync_key;
+            let mut resumption = Resumption::of(&parameter_key, &sync_key);
+            let mut keys = SessionKeys::new(&parameter_key, &sync_key);
+            let mut attractor = AttractorKind::Lorenz.build(&parameter_key);
+            let mut seed = attractor.initial_state();
+            let mut synced_state = seed.clone();
+            // whether synced_state was agreed on under the current keys, so further
+            // messages can be sent from it without syncing again
+            let mut synced = false;
+            let mut detector = SyncDetector::new(options.sync);
+            // envelope counters are per session, which is per connection
+            let mut replay_window = ReplayWindow::new();
             let mut time = SystemTime::now();
 
             loop {
@@ -91,215 +575,603 @@ fn main() {
                     ServerState::Unverified => {
                         println!("Starting Key exchange with Client {}", i);
 
-                        let server_secret_key = EphemeralSecret::random_from_rng(OsRng);
-                        let server_public_key = PublicKey::from(&server_secret_key);
-                        let shared_secret;
-
-                        match websocket.read() {
-                            Ok(Message::Binary(client_public_key_bytes)) => {
-                                let mut byte_array: [u8; 32] = [0; 32];
-                                byte_array[..32]
-                                    .copy_from_slice(client_public_key_bytes.as_slice());
-                                let client_public_key = PublicKey::from(byte_array);
-
-                                shared_secret =
-                                    server_secret_key.diffie_hellman(&client_public_key);
-                            }
-                            _ => panic!("Recieved Invalid Key"),
+                        let outcome =
+                            match handshake::respond(&mut websocket, &static_key, &ticket_key) {
+                                Ok(outcome) => outcome,
+                                Err(e) => {
+                                    println!("Key exchange with Client {} failed: {}", i, e);
+                                    break;
+                                }
+                            };
+                        println!("Key confirmed with Client {}", i);
+                        if outcome.restored.is_some() {
+                            println!("Resumed session with Client {}", i);
                         }
 
-                        websocket
-                            .send(Message::Binary(server_public_key.to_bytes().to_vec()))
-                            .expect("Could not send public key");
+                        sync_key = outcome.chaining_key;
+                        parameter_key = outcome.parameter_key();
+                        resumption = Resumption::of(&parameter_key, &sync_key);
+                        keys = SessionKeys::new(&parameter_key, &sync_key);
+                        attractor = AttractorKind::Lorenz.build(&parameter_key);
+                        seed = attractor.step(&seed);
 
-                        let result = shared_secret.to_bytes()[10];
-                        let rho = common::lin_interp(result as f64, 0.0, 24.0, 255.0, 57.0);
-                        let sigma = common::interpolate_sigma(rho);
+                        // from here on blocking reads wake up to check on the session
+                        if let Err(e) = websocket.get_mut().set_read_timeout(Some(POLL_INTERVAL)) {
+                            println!("Lost the connection to Client {}: {}", i, e);
+                            break;
+                        }
 
-                        println!("rho = {}", rho);
-                        println!("sigma = {}", sigma);
+                        stream_state = ServerState::Unsynced;
+                    }
+                    ServerState::Unsynced => {
+                        if !set_nonblocking(&mut websocket, i, true) {
+                            break;
+                        }
 
-                        seed = common::lorenz_attractor(
-                            seed.0, None, seed.1, seed.2, sigma, rho, beta, h,
-                        );
+                        seed = attractor.step(&seed);
 
-                        stream_state = ServerState::Unsynced { rho, sigma };
-                    }
-                    ServerState::Unsynced { rho, sigma } => {
-                        websocket
-                            .get_mut()
-                            .set_nonblocking(true)
-                            .expect("Couldn't make socket non-blocking");
-
-                        let (new_x, new_y, new_z) = common::lorenz_attractor(
-                            seed.0, None, seed.1, seed.2, sigma, rho, beta, h,
-                        );
-                        seed = (new_x, new_y, new_z);
+                        // between messages is where a session can be ended without
+                        // cutting one off
+                        if shutdown.load(Ordering::SeqCst) {
+                            Expiry::ShuttingDown.end(&mut websocket, i);
+                            break;
+                        }
+                        if last_heard.elapsed() >= options.idle_timeout {
+                            Expiry::Idle.end(&mut websocket, i);
+                            break;
+                        }
+                        if last_ping.elapsed() >= options.ping_interval {
+                            // a client that has gone away shows up as a failed write
+                            if !send(&mut websocket, i, Message::Ping(Vec::new())) {
+                                break;
+                            }
+                            last_ping = Instant::now();
+                        }
 
-                        match common::read_non_blocking(&mut websocket) {
-                            Some(Message::Binary(v)) => match v.as_slice() {
-                                [1] => {
+                        let v = match common::read_non_blocking(&mut websocket) {
+                            Ok(Some(Message::Binary(v))) => v,
+                            Ok(Some(Message::Close(_))) => {
+                                println!("Client {} closed the connection", i);
+                                break;
+                            }
+                            // pongs say the client is there, not that it is busy
+                            Ok(_) => continue,
+                            Err(e) => {
+                                println!("Lost the connection to Client {}: {}", i, e);
+                                break;
+                            }
+                        };
+                        last_heard = Instant::now();
+                        match v.as_slice() {
+                            [1, request @ ..] => {
+                                time = SystemTime::now();
+                                let Some((kind, scheme)) =
+                                    approve_sync_request(&mut websocket, i, request)
+                                else {
+                                    break;
+                                };
+                                attractor = kind.build(&parameter_key);
+                                if seed.len() != attractor.dimension() {
+                                    seed = attractor.initial_state();
+                                }
+                                detector.reset();
+                                stream_state = ServerState::Syncing { scheme };
+                            }
+                            [ticket::TICKET_REQUEST] => {
+                                println!("Received: Ticket Request");
+                                let mut response = vec![ticket::NEW_TICKET];
+                                response.extend(ticket_key.issue(&resumption));
+                                if !send(&mut websocket, i, Message::Binary(response)) {
+                                    break;
+                                }
+                                println!("Sent: Resumption Ticket");
+                            }
+                            // another message in flight, keyed from the last sync
+                            [3, ..] => {
+                                let Some((encoding, id)) = parse_encrypted_request(&v) else {
+                                    protocol_error(&mut websocket, i, "invalid message request");
+                                    break;
+                                };
+                                if synced {
                                     time = SystemTime::now();
-                                    println!("Received: Sync Request");
-                                    websocket
-                                        .send(Message::Text("Sync Request approved".to_string()))
-                                        .unwrap();
-
-                                    println!("Sent: Sync Request approved");
-                                    stream_state = ServerState::Syncing { rho, sigma };
+                                    stream_state = ServerState::Encrypted { encoding, id };
+                                } else {
+                                    // the envelope still follows, and is dropped unread
+                                    if !set_nonblocking(&mut websocket, i, false) {
+                                        break;
+                                    }
+                                    if read_frame(&mut websocket, i, &mut deadline).is_none()
+                                        || !refuse(
+                                            &mut websocket,
+                                            i,
+                                            id,
+                                            RefusalCode::Alignment,
+                                            "nothing has been synced under these keys",
+                                        )
+                                    {
+                                        break;
+                                    }
                                 }
-                                [0] => {
-                                    println!("Received: Cancel Request");
-                                    println!("Client Number {} Left", i);
+                            }
+                            [rekey::REKEY, ..] => {
+                                if !keys.verify_request(&v) {
+                                    protocol_error(&mut websocket, i, "invalid rekey request");
                                     break;
                                 }
-                                _ => panic!("Invalid Request Received"),
-                            },
-                            _ => (),
+                                keys.ratchet();
+                                sync_key = keys.sync_key;
+                                parameter_key = keys.parameter_key;
+                                synced = false;
+                                if !send(&mut websocket, i, Message::Binary(keys.acknowledgement()))
+                                {
+                                    break;
+                                }
+                                println!("Rekeyed with Client {} to epoch {}", i, keys.epoch);
+                            }
+                            [0] => {
+                                println!("Received: Cancel Request");
+                                println!("Client Number {} Left", i);
+                                break;
+                            }
+                            _ => {
+                                protocol_error(&mut websocket, i, "invalid request");
+                                break;
+                            }
                         }
                     }
-                    ServerState::Syncing { rho, sigma } => {
-                        websocket
-                            .get_mut()
-                            .set_nonblocking(false)
-                            .expect("Couldn't make socket blocking");
-
-                        match (websocket.read(), websocket.read(), websocket.read()) {
-                            (
-                                Ok(Message::Binary(x_prime_msg)),
-                                Ok(Message::Binary(y_prime_msg)),
-                                Ok(Message::Binary(z_prime_msg)),
-                            ) => {
-                                let x_prime =
-                                    f64::from_ne_bytes(x_prime_msg[0..8].try_into().unwrap());
-                                let y_prime =
-                                    f64::from_ne_bytes(y_prime_msg[0..8].try_into().unwrap());
-                                let z_prime =
-                                    f64::from_ne_bytes(z_prime_msg[0..8].try_into().unwrap());
-                                let (new_x, new_y, new_z) = common::lorenz_attractor(
-                                    seed.0,
-                                    Some(x_prime),
-                                    seed.1,
-                                    seed.2,
-                                    sigma,
-                                    rho,
-                                    beta,
-                                    h,
+                    ServerState::Syncing { ref scheme } => {
+                        if !set_nonblocking(&mut websocket, i, false) {
+                            break;
+                        }
+
+                        let batch = match read_frame(&mut websocket, i, &mut deadline) {
+                            Some(Message::Binary(v)) => DriveBatch::from_bytes(&v),
+                            Some(_) => None,
+                            None => break,
+                        };
+                        let batch = match batch {
+                            Some(batch) if batch.first_step == detector.steps() as u64 + 1 => batch,
+                            _ => {
+                                protocol_error(&mut websocket, i, "invalid drive batch");
+                                break;
+                            }
+                        };
+
+                        let mut status = SyncStatus::Converging;
+                        for &x_prime in batch.x.iter() {
+                            // only the drive ever leaves the client, so how well we
+                            // predicted it is our measure of the error
+                            let error = (seed[0] - x_prime).abs();
+                            seed = scheme.step(attractor.as_ref(), &seed, x_prime);
+
+                            status = detector.observe(error, x_prime.abs());
+                            if status != SyncStatus::Converging {
+                                break;
+                            }
+                        }
+                        println!("{:?}", seed);
+
+                        match status {
+                            SyncStatus::Synced => {
+                                println!("Sync Complete");
+
+                                // both sides snap the state of this step to the same grid
+                                seed = sync::quantize_state(&seed, detector.config().quantum);
+                                synced_state = seed.clone();
+                                synced = true;
+
+                                // the client checks it landed on the same state against
+                                // this, without us having to send the state itself
+                                let step = detector.steps() as u64;
+                                let mut msg = vec![2];
+                                msg.extend_from_slice(&step.to_le_bytes());
+                                msg.extend_from_slice(&sync::commitment(&sync_key, step, &seed));
+                                if !send(&mut websocket, i, Message::Binary(msg)) {
+                                    break;
+                                }
+                                println!("Sent: Sync Complete");
+
+                                // the client's message is due from here
+                                last_heard = Instant::now();
+                                stream_state = ServerState::Synced;
+                            }
+                            SyncStatus::Failed => {
+                                println!(
+                                    "Sync with Client {} failed after {} steps",
+                                    i,
+                                    detector.steps()
                                 );
-                                seed = (new_x, new_y, new_z);
-                                println!("{}, {}, {}", new_x, new_y, new_z);
-                                println!("{}, {}", rho, sigma);
-
-                                if last_y == y_prime && last_z == z_prime {
-                                    println!("Synced!!");
-                                    sync_count += 1;
-                                    if sync_count == 100 {
-                                        println!("Sync Complete");
-                                        common::send_request(&mut websocket, "Sync Complete", 2);
-                                        stream_state = ServerState::Synced { rho, sigma };
-                                    }
-                                } else {
-                                    sync_count = 0;
+                                if !send(&mut websocket, i, Message::Binary(vec![4])) {
+                                    break;
                                 }
+                                println!("Sent: Sync Failed");
 
-                                last_y = new_y;
-                                last_z = new_z;
+                                // start the next attempt from somewhere else
+                                seed = sync::perturb(&seed, 1.0);
+                                stream_state = ServerState::Unsynced;
+                            }
+                            SyncStatus::Converging => {
+                                if !send(&mut websocket, i, Message::Binary(vec![6])) {
+                                    break;
+                                }
+                                println!("Sent: Sync Continue");
                             }
-                            _ => panic!("Received invalid data format"),
                         }
                     }
-                    ServerState::Synced { rho, sigma } => {
-                        websocket
-                            .get_mut()
-                            .set_nonblocking(true)
-                            .expect("Couldn't make socket non-blocking");
-
-                        let (new_x, new_y, new_z) = common::lorenz_attractor(
-                            seed.0, None, seed.1, seed.2, sigma, rho, beta, h,
-                        );
-                        seed = (new_x, new_y, new_z);
+                    ServerState::Synced => {
+                        if !set_nonblocking(&mut websocket, i, true) {
+                            break;
+                        }
+
+                        seed = attractor.step(&seed);
 
-                        let bytes = seed.1.to_ne_bytes();
-                        bytes.iter().for_each(|e| key_stream.push(*e));
+                        // the client owes us a message, which a shutdown still waits
+                        // on for a moment
+                        if let Some(expiry) = deadline.check(last_heard) {
+                            expiry.end(&mut websocket, i);
+                            break;
+                        }
 
                         match common::read_non_blocking(&mut websocket) {
-                            Some(Message::Binary(v)) if v.as_slice() == [3] => {
-                                stream_state = ServerState::Encrypted { rho, sigma }
+                            Ok(Some(Message::Binary(v))) if v.first() == Some(&3) => {
+                                let Some((encoding, id)) = parse_encrypted_request(&v) else {
+                                    protocol_error(&mut websocket, i, "invalid message request");
+                                    break;
+                                };
+                                stream_state = ServerState::Encrypted { encoding, id }
+                            }
+                            Ok(Some(Message::Binary(v))) if v.first() == Some(&7) => {
+                                let Some((encoding, id)) = parse_masked_request(&v) else {
+                                    protocol_error(&mut websocket, i, "invalid masked message");
+                                    break;
+                                };
+                                println!("Received: Masked Message");
+                                stream_state = ServerState::Unmasking { encoding, id }
+                            }
+                            Ok(Some(Message::Binary(v))) if matches!(v.first(), Some(8 | 9)) => {
+                                let Some((id, key_stream, nonce)) = parse_bulk_request(&v) else {
+                                    protocol_error(&mut websocket, i, "invalid bulk message");
+                                    break;
+                                };
+                                println!("Received: Bulk Encrypted Message ({:?})", key_stream);
+                                stream_state = ServerState::BulkDecrypting {
+                                    key_stream,
+                                    nonce,
+                                    id,
+                                }
+                            }
+                            Ok(Some(Message::Binary(v)))
+                                if v.first() == Some(&chunk::CHUNKED_MESSAGE) =>
+                            {
+                                let Some(header) = ChunkedHeader::from_bytes(&v, &sync_key) else {
+                                    protocol_error(&mut websocket, i, "invalid chunked header");
+                                    break;
+                                };
+                                println!(
+                                    "Received: Chunked Message ({} bytes in {} chunks)",
+                                    header.total_len, header.chunk_count
+                                );
+                                stream_state = ServerState::Receiving {
+                                    reassembly: Reassembly::new(header),
+                                }
+                            }
+                            // the client's state didn't match our commitment
+                            Ok(Some(Message::Binary(v))) if v.first() == Some(&1) => {
+                                println!("Client {} rejected the synced state", i);
+                                time = SystemTime::now();
+                                let Some((kind, scheme)) =
+                                    approve_sync_request(&mut websocket, i, &v[1..])
+                                else {
+                                    break;
+                                };
+                                attractor = kind.build(&parameter_key);
+                                if seed.len() != attractor.dimension() {
+                                    seed = attractor.initial_state();
+                                }
+                                detector.reset();
+                                stream_state = ServerState::Syncing { scheme };
+                            }
+                            Ok(Some(Message::Binary(v))) if v.as_slice() == [0] => {
+                                println!("Received: Cancel Request");
+                                println!("Client Number {} Left", i);
+                                break;
+                            }
+                            Ok(Some(Message::Close(_))) => {
+                                println!("Client {} closed the connection", i);
+                                break;
+                            }
+                            Ok(Some(Message::Binary(_) | Message::Text(_))) => {
+                                protocol_error(&mut websocket, i, "invalid request");
+                                break;
+                            }
+                            Err(e) => {
+                                println!("Lost the connection to Client {}: {}", i, e);
+                                break;
                             }
                             _ => (),
                         }
                     }
-                    ServerState::Encrypted { rho, sigma } => {
-                        websocket
-                            .get_mut()
-                            .set_nonblocking(false)
-                            .expect("Couldn't make socket blocking");
-                        match websocket.read() {
-                            Ok(Message::Text(ciphertext)) => {
-                                println!("Received ciphertext = {}", ciphertext);
-
-                                let mut first_float = Vec::new();
-
-                                for _ in 0..8 {
-                                    match websocket.read() {
-                                        Ok(Message::Binary(byte)) => first_float.push(byte),
-                                        _ => panic!("Could not read byte"),
+                    ServerState::Encrypted { encoding, id } => {
+                        if !set_nonblocking(&mut websocket, i, false) {
+                            break;
+                        }
+                        let envelope = match open_envelope(
+                            &mut websocket,
+                            i,
+                            &mut deadline,
+                            encoding,
+                            id,
+                            &sync_key,
+                            &mut replay_window,
+                        ) {
+                            Opened::Envelope(envelope) => envelope,
+                            Opened::Refused => {
+                                stream_state = ServerState::Unsynced;
+                                continue;
+                            }
+                            Opened::Closed => break,
+                        };
+                        println!(
+                            "Received ciphertext = {}",
+                            BASE64_STANDARD.encode(&envelope.ciphertext)
+                        );
+
+                        let offset = envelope.key_stream_offset as usize;
+                        if offset > MAX_KEY_STREAM_OFFSET {
+                            let reason = format!("key stream offset {} is too large", offset);
+                            if !refuse(&mut websocket, i, id, RefusalCode::Alignment, reason) {
+                                break;
+                            }
+                            stream_state = ServerState::Unsynced;
+                            continue;
+                        }
+
+                        let start = nonce::initial_state(
+                            attractor.as_ref(),
+                            &sync_key,
+                            &synced_state,
+                            &envelope.nonce,
+                        );
+                        let mut key_stream = vec![0; offset + 16];
+                        AttractorKeyStream::new(attractor.as_ref(), &start).fill(&mut key_stream);
+                        nonce::mask(&sync_key, &envelope.nonce, &mut key_stream);
+
+                        let decoded_message = decrypt(&envelope, &key_stream[offset..]);
+                        let plaintext = match envelope.content_type {
+                            ContentType::Text => match String::from_utf8(decoded_message) {
+                                Ok(plaintext) => plaintext,
+                                Err(_) => {
+                                    let reason = "the plaintext isn't valid UTF-8";
+                                    if !refuse(&mut websocket, i, id, RefusalCode::Decode, reason) {
+                                        break;
                                     }
+                                    stream_state = ServerState::Unsynced;
+                                    continue;
                                 }
+                            },
+                            ContentType::Bytes => BASE64_STANDARD.encode(decoded_message),
+                        };
+                        if !send_receipt(&mut websocket, i, Receipt::Delivered(id)) {
+                            break;
+                        }
+                        stream_state = ServerState::Decrypted { plaintext }
+                    }
+                    ServerState::Unmasking { encoding, id } => {
+                        if !set_nonblocking(&mut websocket, i, false) {
+                            break;
+                        }
+
+                        let Envelope {
+                            nonce,
+                            ciphertext: mut signal,
+                            ..
+                        } = match open_envelope(
+                            &mut websocket,
+                            i,
+                            &mut deadline,
+                            encoding,
+                            id,
+                            &sync_key,
+                            &mut replay_window,
+                        ) {
+                            Opened::Envelope(envelope) => envelope,
+                            Opened::Refused => {
+                                stream_state = ServerState::Unsynced;
+                                continue;
+                            }
+                            Opened::Closed => break,
+                        };
+                        nonce::mask(&sync_key, &nonce, &mut signal);
+                        let Some(DriveBatch { x: signal, .. }) = DriveBatch::from_bytes(&signal)
+                        else {
+                            let reason = "the masked signal couldn't be read";
+                            if !refuse(&mut websocket, i, id, RefusalCode::Decode, reason) {
+                                break;
+                            }
+                            stream_state = ServerState::Unsynced;
+                            continue;
+                        };
+                        println!("Received masked signal of {} steps", signal.len());
 
-                                let first_float =
-                                    first_float.into_iter().flatten().collect::<Vec<u8>>();
+                        // the client masked from the synced state mixed with the nonce
+                        let start = nonce::initial_state(
+                            attractor.as_ref(),
+                            &sync_key,
+                            &synced_state,
+                            &nonce,
+                        );
+                        let decoded_message = masking::unmask(&signal, attractor.as_ref(), &start);
+                        let Some(next) = deliver(&mut websocket, i, id, decoded_message) else {
+                            break;
+                        };
+                        stream_state = next;
+                    }
+                    ServerState::BulkDecrypting {
+                        key_stream,
+                        nonce,
+                        id,
+                    } => {
+                        if !set_nonblocking(&mut websocket, i, false) {
+                            break;
+                        }
 
-                                match key_stream
-                                    .windows(8)
-                                    .position(|window| window == &first_float[0..8])
-                                {
-                                    Some(index) => {
-                                        let new_key_stream = key_stream[index..index + 16].to_vec();
-                                        let decoded_message = decrypt(&ciphertext, &new_key_stream);
-                                        stream_state = ServerState::Decrypted {
-                                            rho,
-                                            sigma,
-                                            plaintext: String::from_utf8(decoded_message).unwrap(),
+                        let ciphertext = match read_frame(&mut websocket, i, &mut deadline) {
+                            Some(Message::Text(ciphertext)) => Some(ciphertext),
+                            Some(_) => None,
+                            None => break,
+                        };
+                        let decoded = ciphertext
+                            .as_ref()
+                            .and_then(|ciphertext| BASE64_STANDARD.decode(ciphertext).ok());
+                        let Some(mut decoded_message) = decoded else {
+                            let reason = "the ciphertext isn't base64 text";
+                            if !refuse(&mut websocket, i, id, RefusalCode::Decode, reason) {
+                                break;
+                            }
+                            stream_state = ServerState::Unsynced;
+                            continue;
+                        };
+                        println!("Received ciphertext = {}", ciphertext.unwrap_or_default());
+
+                        let start = nonce::initial_state(
+                            attractor.as_ref(),
+                            &sync_key,
+                            &synced_state,
+                            &nonce,
+                        );
+                        key_stream
+                            .build(attractor.as_ref(), &sync_key, &start)
+                            .apply(&mut decoded_message);
+                        nonce::mask(&sync_key, &nonce, &mut decoded_message);
+                        let Some(next) = deliver(&mut websocket, i, id, decoded_message) else {
+                            break;
+                        };
+                        stream_state = next;
+                    }
+                    ServerState::Receiving { ref mut reassembly } => {
+                        if !set_nonblocking(&mut websocket, i, false) {
+                            break;
+                        }
+
+                        match read_frame(&mut websocket, i, &mut deadline) {
+                            Some(Message::Binary(v)) if v.first() == Some(&chunk::CHUNK) => {
+                                match Chunk::from_bytes(&v) {
+                                    Some(chunk) => {
+                                        if let Err(rejection) = reassembly.insert(&sync_key, &chunk)
+                                        {
+                                            println!(
+                                                "Dropped chunk {}: {:?}",
+                                                chunk.sequence, rejection
+                                            );
                                         }
                                     }
-                                    None => {
-                                        panic!("Item {:?} not found in the vector.", first_float)
-                                    }
+                                    None => println!("Dropped a malformed chunk"),
+                                }
+                            }
+                            Some(Message::Binary(v)) if v.as_slice() == [chunk::CHUNKS_SENT] => {
+                                let missing = reassembly.missing();
+                                let msg = Message::Binary(chunk::missing_to_bytes(&missing));
+                                if !send(&mut websocket, i, msg) {
+                                    break;
+                                }
+
+                                if let Some(ciphertext) = reassembly.ciphertext() {
+                                    println!(
+                                        "Received all {} chunks",
+                                        reassembly.header().chunk_count
+                                    );
+                                    let header = reassembly.header();
+                                    let start = nonce::initial_state(
+                                        attractor.as_ref(),
+                                        &sync_key,
+                                        &synced_state,
+                                        &header.nonce,
+                                    );
+                                    let mut decoded_message = ciphertext.to_vec();
+                                    header
+                                        .key_stream
+                                        .build(attractor.as_ref(), &sync_key, &start)
+                                        .apply(&mut decoded_message);
+                                    nonce::mask(&sync_key, &header.nonce, &mut decoded_message);
+                                    let id = header.id;
+                                    let Some(next) =
+                                        deliver(&mut websocket, i, id, decoded_message)
+                                    else {
+                                        break;
+                                    };
+                                    stream_state = next;
+                                } else {
+                                    println!(
+                                        "Asked client {} for {} missing chunks",
+                                        i,
+                                        missing.len()
+                                    );
+                                }
+                            }
+                            Some(Message::Binary(v)) if v.as_slice() == [0] => {
+                                println!("Received: Cancel Request");
+                                println!("Client Number {} Left", i);
+                                break;
+                            }
+                            Some(_) => {
+                                let id = reassembly.header().id;
+                                let reason = "expected a chunk";
+                                if !refuse(&mut websocket, i, id, RefusalCode::Decode, reason) {
+                                    break;
                                 }
+                                stream_state = ServerState::Unsynced;
                             }
-                            _ => panic!("Invalid message received"),
+                            None => break,
                         }
                     }
-                    ServerState::Decrypted {
-                        rho,
-                        sigma,
-                        ref plaintext,
-                    } => {
+                    ServerState::Decrypted { ref plaintext } => {
                         println!("Decoded message from client {}: {}", i, plaintext.trim());
                         println!("Took: {}ms", time.elapsed().unwrap().as_millis());
 
                         // desync the attractors
-                        seed = (seed.0 + 0.1, seed.1 - 0.1, seed.2 + 0.1);
-                        stream_state = ServerState::Unsynced { rho, sigma };
+                        seed = sync::perturb(&seed, 0.1);
+                        last_heard = Instant::now();
+                        stream_state = ServerState::Unsynced;
                     }
                 }
             }
-        });
+        }));
     }
+
+    // sessions in the middle of a message get `DRAIN_TIMEOUT` to finish it
+    println!(
+        "Shutting down, waiting on {} sessions",
+        open_sessions.load(Ordering::SeqCst)
+    );
+    for session in sessions {
+        let _ = session.join();
+    }
+    println!("Server stopped");
 }
 
 #[cfg(test)]
 mod unit_tests {
     use super::*;
-    use strange_cipher::testing_common::generate_key_stream;
+    use strange_cipher::{attractor, nonce::NONCE_LEN, testing_common::generate_key_stream};
+
+    fn sealed(base64_ciphertext: &str) -> Envelope {
+        let ciphertext = BASE64_STANDARD.decode(base64_ciphertext).unwrap();
+        Envelope::seal(
+            &[0; 32],
+            [0; NONCE_LEN],
+            0,
+            0,
+            ContentType::Text,
+            ciphertext,
+        )
+    }
 
     #[test]
     fn test_decrypt_synced() {
         let key_stream = generate_key_stream();
 
-        let encrypted_message = "QrLPHFImLZRvpNcZU20s";
-        let decrypted = String::from_utf8(decrypt(encrypted_message, &key_stream)).unwrap();
+        let encrypted_message = sealed("QrLPHFImLZRvpNcZU20s");
+        let decrypted = String::from_utf8(decrypt(&encrypted_message, &key_stream)).unwrap();
         assert_eq!("Hello, Testing!", decrypted);
     }
 
@@ -307,11 +1179,91 @@ mod unit_tests {
     fn test_decrypt_empty_message_synced() {
         let key_stream = generate_key_stream();
 
-        let encrypted_message = "";
-        let decrypted = String::from_utf8(decrypt(encrypted_message, &key_stream)).unwrap();
+        let encrypted_message = sealed("");
+        let decrypted = String::from_utf8(decrypt(&encrypted_message, &key_stream)).unwrap();
         assert_eq!("", decrypted);
     }
 
-    // TODO: sync test
-    // TODO: sync + decrypt test
+    #[test]
+    fn test_requests_carry_the_message_id() {
+        let id = 0x0102_0304_0506_0708u64.to_le_bytes();
+        let nonce = [4; NONCE_LEN];
+
+        assert_eq!(
+            parse_masked_request(&[&[7, Encoding::Cbor as u8], &id[..]].concat()),
+            Some((Encoding::Cbor, 0x0102030405060708))
+        );
+        assert_eq!(parse_masked_request(&[&[7], &id[..]].concat()), None);
+        assert_eq!(parse_masked_request(&[7]), None);
+        assert_eq!(
+            parse_bulk_request(&[&[8], &id[..], &nonce].concat()),
+            Some((0x0102030405060708, BulkKeyStream::Lattice, nonce))
+        );
+        assert_eq!(
+            parse_bulk_request(&[&[9], &id[..], &[3], &nonce].concat()),
+            Some((
+                0x0102030405060708,
+                BulkKeyStream::Parallel { trajectories: 3 },
+                nonce
+            ))
+        );
+        assert_eq!(
+            parse_bulk_request(&[&[9], &id[..], &[0], &nonce].concat()),
+            None
+        );
+        assert_eq!(
+            parse_bulk_request(&[&[8], &id[..], &nonce[1..]].concat()),
+            None
+        );
+        assert_eq!(parse_bulk_request(&[8, 1, 2]), None);
+    }
+
+    #[test]
+    fn test_decrypt_after_sync() {
+        let attractor = AttractorKind::Lorenz.build(&[99; 32]);
+        let attractor = attractor.as_ref();
+        let mut detector = SyncDetector::new(SyncConfig::default());
+
+        let mut client = attractor.initial_state();
+        let mut server = vec![1.0, 1.0, 2.0];
+        loop {
+            client = attractor.step(&client);
+            let error = sync::state_error(&server, &client);
+            server = attractor::driven_step(attractor, &server, client[0]);
+
+            match detector.observe(error, client[1].abs()) {
+                SyncStatus::Synced => break,
+                SyncStatus::Failed => panic!("Attractors never synced"),
+                SyncStatus::Converging => (),
+            }
+        }
+
+        let quantum = detector.config().quantum;
+        client = sync::quantize_state(&attractor.step(&client), quantum);
+        server = sync::quantize_state(&server, quantum);
+
+        let nonce = nonce::generate();
+        client = nonce::initial_state(attractor, &[99; 32], &client, &nonce);
+        server = nonce::initial_state(attractor, &[99; 32], &server, &nonce);
+
+        let mut client_stream = Vec::new();
+        let mut server_stream = Vec::new();
+        while client_stream.len() < 16 {
+            client = attractor.step(&client);
+            server = attractor.step(&server);
+            client_stream.extend(attractor::key_bytes(attractor, &client));
+            server_stream.extend(attractor::key_bytes(attractor, &server));
+        }
+
+        let message = "Hello, Syncing!";
+        let ciphertext: Vec<u8> = message
+            .bytes()
+            .enumerate()
+            .map(|(i, byte)| byte ^ client_stream[i % client_stream.len()])
+            .collect();
+
+        let envelope = Envelope::seal(&[99; 32], nonce, 0, 0, ContentType::Text, ciphertext);
+        let decrypted = decrypt(&envelope, &server_stream);
+        assert_eq!(message, String::from_utf8(decrypted).unwrap());
+    }
 }
@@ -0,0 +1,450 @@
+use std::io::{self, Read, Write};
+
+use crate::{
+    attractor::{self, Attractor},
+    lattice::CoupledMapLattice,
+    parallel::ParallelKeyStream,
+};
+
+/// A source of key bytes that carries on where the last call stopped, however
+/// the bytes are asked for
+pub trait KeyStream {
+    fn fill(&mut self, key: &mut [u8]);
+
+    /// XORs the next key bytes into `data`, which encrypts and decrypts alike
+    fn apply(&mut self, data: &mut [u8]) {
+        let mut key = vec![0; data.len()];
+        self.fill(&mut key);
+        data.iter_mut()
+            .zip(key)
+            .for_each(|(byte, key)| *byte ^= key);
+    }
+}
+
+/// The key bytes of a single trajectory, one step at a time
+pub struct AttractorKeyStream<'a> {
+    attractor: &'a dyn Attractor,
+    state: Vec<f64>,
+    // key bytes of the last step that were not needed yet
+    pending: Vec<u8>,
+}
+
+impl<'a> AttractorKeyStream<'a> {
+    /// The first key bytes come from the step after `state`
+    pub fn new(attractor: &'a dyn Attractor, state: &[f64]) -> AttractorKeyStream<'a> {
+        AttractorKeyStream {
+            attractor,
+            state: state.to_vec(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> &[f64] {
+        &self.state
+    }
+}
+
+impl KeyStream for AttractorKeyStream<'_> {
+    fn fill(&mut self, key: &mut [u8]) {
+        while self.pending.len() < key.len() {
+            self.state = self.attractor.step(&self.state);
+            self.pending
+                .extend(attractor::key_bytes(self.attractor, &self.state));
+        }
+        key.copy_from_slice(&self.pending[..key.len()]);
+        self.pending.drain(..key.len());
+    }
+}
+
+/// The generators for messages too long for the repeating 16-byte key, both
+/// seeded from the synced state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkKeyStream {
+    Lattice,
+    Parallel { trajectories: u8 },
+}
+
+impl BulkKeyStream {
+    pub fn build<'a>(
+        &self,
+        attractor: &'a dyn Attractor,
+        secret: &[u8; 32],
+        state: &[f64],
+    ) -> Box<dyn KeyStream + 'a> {
+        match *self {
+            BulkKeyStream::Lattice => Box::new(CoupledMapLattice::from_state(secret, state)),
+            BulkKeyStream::Parallel { trajectories } => Box::new(ParallelKeyStream::new(
+                attractor,
+                secret,
+                state,
+                trajectories as usize,
+            )),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            BulkKeyStream::Lattice => vec![0],
+            BulkKeyStream::Parallel { trajectories } => vec![1, *trajectories],
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<BulkKeyStream> {
+        match bytes {
+            [0] => Some(BulkKeyStream::Lattice),
+            [1, trajectories] if *trajectories > 0 => Some(BulkKeyStream::Parallel {
+                trajectories: *trajectories,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Encrypts everything written through it with a live key stream. The key bytes
+/// for a write are spent as soon as it is accepted, so whatever `inner` doesn't
+/// take at once is kept and sent ahead of the next write or on `flush`
+pub struct EncryptWriter<W, K> {
+    inner: W,
+    key_stream: K,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write, K: KeyStream> EncryptWriter<W, K> {
+    pub fn new(inner: W, key_stream: K) -> EncryptWriter<W, K> {
+        EncryptWriter {
+            inner,
+            key_stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.buffer.is_empty() {
+            match self.inner.write(&self.buffer) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.buffer.drain(..written);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes what is left and hands back the inner writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write, K: KeyStream> Write for EncryptWriter<W, K> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.drain()?;
+
+        self.buffer.extend_from_slice(buf);
+        self.key_stream.apply(&mut self.buffer);
+        // the bytes are ours now, a failure shows up again on the next call
+        let _ = self.drain();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+/// Decrypts everything read through it with a live key stream
+pub struct DecryptReader<R, K> {
+    inner: R,
+    key_stream: K,
+}
+
+impl<R: Read, K: KeyStream> DecryptReader<R, K> {
+    pub fn new(inner: R, key_stream: K) -> DecryptReader<R, K> {
+        DecryptReader { inner, key_stream }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read, K: KeyStream> Read for DecryptReader<R, K> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.key_stream.apply(&mut buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "async")]
+pub use self::non_blocking::{AsyncDecryptReader, AsyncEncryptWriter};
+
+#[cfg(feature = "async")]
+mod non_blocking {
+    use std::{
+        io,
+        pin::Pin,
+        task::{ready, Context, Poll},
+    };
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use super::KeyStream;
+
+    /// `EncryptWriter` for tokio writers
+    pub struct AsyncEncryptWriter<W, K> {
+        inner: W,
+        key_stream: K,
+        buffer: Vec<u8>,
+    }
+
+    impl<W: AsyncWrite + Unpin, K: KeyStream + Unpin> AsyncEncryptWriter<W, K> {
+        pub fn new(inner: W, key_stream: K) -> AsyncEncryptWriter<W, K> {
+            AsyncEncryptWriter {
+                inner,
+                key_stream,
+                buffer: Vec::new(),
+            }
+        }
+
+        pub fn into_inner(self) -> W {
+            self.inner
+        }
+
+        fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            while !self.buffer.is_empty() {
+                match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buffer))? {
+                    0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                    written => {
+                        self.buffer.drain(..written);
+                    }
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin, K: KeyStream + Unpin> AsyncWrite for AsyncEncryptWriter<W, K> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            ready!(this.poll_drain(cx))?;
+
+            this.buffer.extend_from_slice(buf);
+            this.key_stream.apply(&mut this.buffer);
+            // the bytes are ours now, whatever is left goes out on the next poll
+            let _ = this.poll_drain(cx);
+
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            ready!(this.poll_drain(cx))?;
+            Pin::new(&mut this.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            ready!(this.poll_drain(cx))?;
+            Pin::new(&mut this.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// `DecryptReader` for tokio readers
+    pub struct AsyncDecryptReader<R, K> {
+        inner: R,
+        key_stream: K,
+    }
+
+    impl<R: AsyncRead + Unpin, K: KeyStream + Unpin> AsyncDecryptReader<R, K> {
+        pub fn new(inner: R, key_stream: K) -> AsyncDecryptReader<R, K> {
+            AsyncDecryptReader { inner, key_stream }
+        }
+
+        pub fn into_inner(self) -> R {
+            self.inner
+        }
+    }
+
+    impl<R: AsyncRead + Unpin, K: KeyStream + Unpin> AsyncRead for AsyncDecryptReader<R, K> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            let already = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            this.key_stream.apply(&mut buf.filled_mut()[already..]);
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::{
+        attractor::AttractorKind, lattice::CoupledMapLattice, parallel::ParallelKeyStream,
+    };
+
+    const STATE: [f64; 3] = [-6.100626, -1.896558, 30.187181];
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 % 256) as u8).collect()
+    }
+
+    // hands out at most `limit` bytes per call, like a socket might
+    struct Trickle<T> {
+        inner: T,
+        limit: usize,
+    }
+
+    impl<W: Write> Write for Trickle<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.limit);
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.limit);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_key_stream_does_not_depend_on_how_it_is_split() {
+        let attractor = AttractorKind::HyperLorenz.build(&[5; 32]);
+        let mut whole = vec![0; 1000];
+        AttractorKeyStream::new(attractor.as_ref(), &[1.0, 2.0, 3.0, 4.0]).fill(&mut whole);
+
+        let mut key_stream = AttractorKeyStream::new(attractor.as_ref(), &[1.0, 2.0, 3.0, 4.0]);
+        let mut split = Vec::new();
+        for len in [1, 15, 16, 17, 3, 948] {
+            let mut key = vec![0; len];
+            key_stream.fill(&mut key);
+            split.extend(key);
+        }
+        assert_eq!(split, whole);
+
+        let mut whole = vec![0; 1000];
+        CoupledMapLattice::from_state(&[5; 32], &STATE).fill(&mut whole);
+        let mut lattice = CoupledMapLattice::from_state(&[5; 32], &STATE);
+        let mut split = vec![0; 1000];
+        split.chunks_mut(7).for_each(|chunk| lattice.fill(chunk));
+        assert_eq!(split, whole);
+    }
+
+    #[test]
+    fn test_writer_and_reader_round_trip() {
+        let attractor = AttractorKind::Lorenz.build(&[5; 32]);
+        let message = message(10_000);
+
+        let mut writer = EncryptWriter::new(
+            Trickle {
+                inner: Vec::new(),
+                limit: 100,
+            },
+            AttractorKeyStream::new(attractor.as_ref(), &STATE),
+        );
+        for chunk in message.chunks(333) {
+            writer.write_all(chunk).unwrap();
+        }
+        let ciphertext = writer.into_inner().unwrap().inner;
+        assert_eq!(ciphertext.len(), message.len());
+        assert_ne!(ciphertext, message);
+
+        let mut reader = DecryptReader::new(
+            Trickle {
+                inner: ciphertext.as_slice(),
+                limit: 77,
+            },
+            AttractorKeyStream::new(attractor.as_ref(), &STATE),
+        );
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, message);
+    }
+
+    #[test]
+    fn test_every_generator_streams() {
+        let attractor = AttractorKind::Lorenz.build(&[5; 32]);
+        let message = message(5000);
+
+        let mut writer = EncryptWriter::new(
+            Vec::new(),
+            ParallelKeyStream::new(attractor.as_ref(), &[5; 32], &STATE, 3),
+        );
+        writer.write_all(&message).unwrap();
+        let ciphertext = writer.into_inner().unwrap();
+
+        let mut reader = DecryptReader::new(
+            ciphertext.as_slice(),
+            ParallelKeyStream::new(attractor.as_ref(), &[5; 32], &STATE, 3),
+        );
+        let mut decrypted = vec![0; message.len()];
+        reader.read_exact(&mut decrypted).unwrap();
+        assert_eq!(decrypted, message);
+
+        let mut ciphertext = message.clone();
+        CoupledMapLattice::from_state(&[5; 32], &STATE).apply(&mut ciphertext);
+        let mut reader = DecryptReader::new(
+            ciphertext.as_slice(),
+            CoupledMapLattice::from_state(&[5; 32], &STATE),
+        );
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, message);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_writer_and_reader_round_trip() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let attractor = AttractorKind::Lorenz.build(&[5; 32]);
+        let message = message(10_000);
+
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let (client, server) = tokio::io::duplex(64);
+
+                let mut writer = AsyncEncryptWriter::new(
+                    client,
+                    AttractorKeyStream::new(attractor.as_ref(), &STATE),
+                );
+                let mut reader = AsyncDecryptReader::new(
+                    server,
+                    AttractorKeyStream::new(attractor.as_ref(), &STATE),
+                );
+
+                let send = async {
+                    writer.write_all(&message).await.unwrap();
+                    writer.shutdown().await.unwrap();
+                };
+                let mut decrypted = Vec::new();
+                let receive = reader.read_to_end(&mut decrypted);
+
+                let (_, received) = tokio::join!(send, receive);
+                received.unwrap();
+                assert_eq!(decrypted, message);
+            });
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Copy)]
+pub struct SyncConfig {
+    pub abs_tolerance: f64,
+    pub rel_tolerance: f64,
+    /// Number of consecutive steps the RMS error is measured over
+    pub window: usize,
+    pub max_steps: usize,
+    pub timeout: Duration,
+    /// Grid both sides snap their state to once synced, so tiny leftover
+    /// differences can't leak into the key stream
+    pub quantum: f64,
+    /// How many times the driver restarts a failed sync before giving up
+    pub max_attempts: usize,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            abs_tolerance: 1e-12,
+            rel_tolerance: 1e-14,
+            window: 100,
+            max_steps: 100_000,
+            timeout: Duration::from_secs(10),
+            quantum: 1e-6,
+            max_attempts: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStatus {
+    Converging,
+    Synced,
+    Failed,
+}
+
+pub struct SyncDetector {
+    config: SyncConfig,
+    errors: VecDeque<f64>,
+    references: VecDeque<f64>,
+    steps: usize,
+    started: Instant,
+}
+
+impl SyncDetector {
+    pub fn new(config: SyncConfig) -> SyncDetector {
+        SyncDetector {
+            errors: VecDeque::with_capacity(config.window),
+            references: VecDeque::with_capacity(config.window),
+            config,
+            steps: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn config(&self) -> &SyncConfig {
+        &self.config
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Feeds the distance between the receiver and the driver at one step,
+    /// along with the magnitude of the driver's state for the relative tolerance
+    pub fn observe(&mut self, error: f64, reference: f64) -> SyncStatus {
+        self.steps += 1;
+
+        if self.errors.len() == self.config.window {
+            self.errors.pop_front();
+            self.references.pop_front();
+        }
+        self.errors.push_back(error);
+        self.references.push_back(reference);
+
+        let tolerance =
+            self.config.abs_tolerance + self.config.rel_tolerance * rms(&self.references);
+        if self.errors.len() == self.config.window && self.rms_error() <= tolerance {
+            SyncStatus::Synced
+        } else if self.steps >= self.config.max_steps
+            || self.started.elapsed() > self.config.timeout
+        {
+            SyncStatus::Failed
+        } else {
+            SyncStatus::Converging
+        }
+    }
+
+    pub fn rms_error(&self) -> f64 {
+        rms(&self.errors)
+    }
+
+    pub fn reset(&mut self) {
+        self.errors.clear();
+        self.references.clear();
+        self.steps = 0;
+        self.started = Instant::now();
+    }
+}
+
+fn rms(values: &VecDeque<f64>) -> f64 {
+    if values.is_empty() {
+        return f64::INFINITY;
+    }
+    let sum: f64 = values.iter().map(|v| v * v).sum();
+    (sum / values.len() as f64).sqrt()
+}
+
+/// Euclidean distance between the non-driven coordinates of two states
+pub fn state_error(receiver: &[f64], driver: &[f64]) -> f64 {
+    receiver
+        .iter()
+        .zip(driver.iter())
+        .skip(1)
+        .map(|(r, d)| (r - d).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+pub fn quantize(value: f64, quantum: f64) -> f64 {
+    (value / quantum).round() * quantum
+}
+
+pub fn quantize_state(state: &[f64], quantum: f64) -> Vec<f64> {
+    state
+        .iter()
+        .map(|&value| quantize(value, quantum))
+        .collect()
+}
+
+/// Moves the state by a random offset of up to `magnitude` on every coordinate,
+/// so a retried sync starts from a fresh trajectory
+pub fn perturb(state: &[f64], magnitude: f64) -> Vec<f64> {
+    let mut rng = rand::thread_rng();
+    state
+        .iter()
+        .map(|value| value + rng.gen_range(-magnitude..magnitude))
+        .collect()
+}
+
+pub const DRIVE_BATCH: u8 = 5;
+
+/// Several steps of the drive signal sent in one frame. Only `x` is ever sent,
+/// since `y` and `z` would give the key stream away to anyone listening
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriveBatch {
+    /// Step of the first value, counted from 1 at the start of each sync attempt
+    pub first_step: u64,
+    pub x: Vec<f64>,
+}
+
+impl DriveBatch {
+    pub fn from_states(first_step: u64, states: &[Vec<f64>]) -> DriveBatch {
+        DriveBatch {
+            first_step,
+            x: states.iter().map(|state| state[0]).collect(),
+        }
+    }
+
+    // [DRIVE_BATCH] [first_step: u64] [count: u32] [x; count]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![DRIVE_BATCH];
+        bytes.extend_from_slice(&self.first_step.to_le_bytes());
+        bytes.extend_from_slice(&(self.x.len() as u32).to_le_bytes());
+        self.x
+            .iter()
+            .for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<DriveBatch> {
+        if bytes.len() < 13 || bytes[0] != DRIVE_BATCH {
+            return None;
+        }
+        let first_step = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+        let count = u32::from_le_bytes(bytes[9..13].try_into().unwrap()) as usize;
+
+        let values = &bytes[13..];
+        if values.len() != count * 8 {
+            return None;
+        }
+
+        let x = values
+            .chunks_exact(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        Some(DriveBatch { first_step, x })
+    }
+}
+
+fn commitment_mac(key: &[u8; 32], step: u64, state: &[f64]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(b"sync commitment");
+    mac.update(&step.to_le_bytes());
+    for value in state {
+        mac.update(&value.to_le_bytes());
+    }
+    mac
+}
+
+/// Keyed hash of the quantized responder state at `step`. It lets the driver check
+/// both sides landed on the same state without the state ever being sent
+pub fn commitment(key: &[u8; 32], step: u64, state: &[f64]) -> [u8; 32] {
+    commitment_mac(key, step, state)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+pub fn verify_commitment(key: &[u8; 32], step: u64, state: &[f64], tag: &[u8]) -> bool {
+    commitment_mac(key, step, state).verify_slice(tag).is_ok()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::attractor::{self, Attractor, AttractorKind};
+
+    // Runs a driver and a Pecora-Carroll receiver until the detector settles,
+    // returning the status and both states at that step
+    fn run_sync(
+        attractor: &dyn Attractor,
+        driver: Vec<f64>,
+        receiver: Vec<f64>,
+        coupled: bool,
+        config: SyncConfig,
+    ) -> (SyncStatus, Vec<f64>, Vec<f64>) {
+        let mut detector = SyncDetector::new(config);
+        let mut driver = driver;
+        let mut receiver = receiver;
+
+        loop {
+            driver = attractor.step(&driver);
+            let next = if coupled {
+                attractor::driven_step(attractor, &receiver, driver[0])
+            } else {
+                attractor.step(&receiver)
+            };
+
+            // the receiver's state after taking the driver's x lines up with the
+            // driver's next state
+            let driver_next = attractor.step(&driver);
+            receiver = next;
+
+            let status =
+                detector.observe(state_error(&receiver, &driver_next), driver_next[1].abs());
+            if status != SyncStatus::Converging {
+                return (status, driver_next, receiver);
+            }
+        }
+    }
+
+    fn key_stream(attractor: &dyn Attractor, state: &[f64], len: usize) -> Vec<u8> {
+        let mut state = state.to_vec();
+        let mut key_stream = Vec::new();
+        while key_stream.len() < len {
+            state = attractor.step(&state);
+            key_stream.extend(attractor::key_bytes(attractor, &state));
+        }
+        key_stream
+    }
+
+    #[test]
+    fn test_sync_from_many_offsets() {
+        for secret_byte in [0u8, 37, 101, 180, 255] {
+            let mut secret = [0; 32];
+            secret[10] = secret_byte;
+            let attractor = AttractorKind::Lorenz.build(&secret);
+
+            for offset in 0..20 {
+                let offset = offset as f64;
+                let driver = vec![-10.0 + offset, -7.0 - offset / 2.0, 35.0 - offset];
+                let receiver = vec![1.0 - offset, 1.0 + offset, 2.0 + offset / 3.0];
+
+                let (status, driver, receiver) = run_sync(
+                    attractor.as_ref(),
+                    driver,
+                    receiver,
+                    true,
+                    SyncConfig::default(),
+                );
+                assert_eq!(
+                    status,
+                    SyncStatus::Synced,
+                    "secret byte = {}, offset = {}",
+                    secret_byte,
+                    offset
+                );
+
+                let quantum = SyncConfig::default().quantum;
+                let driver = quantize_state(&driver, quantum);
+                let receiver = quantize_state(&receiver, quantum);
+                assert_eq!(driver, receiver);
+                assert_eq!(
+                    key_stream(attractor.as_ref(), &driver, 64),
+                    key_stream(attractor.as_ref(), &receiver, 64)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_hyperchaotic_lorenz() {
+        for secret_byte in [0u8, 255] {
+            let attractor = AttractorKind::HyperLorenz.build(&[secret_byte; 32]);
+
+            let (status, driver, receiver) = run_sync(
+                attractor.as_ref(),
+                attractor.initial_state(),
+                vec![1.0, 1.0, 2.0, 1.0],
+                true,
+                SyncConfig::default(),
+            );
+            assert_eq!(status, SyncStatus::Synced, "secret byte = {}", secret_byte);
+
+            let quantum = SyncConfig::default().quantum;
+            assert_eq!(
+                quantize_state(&driver, quantum),
+                quantize_state(&receiver, quantum)
+            );
+        }
+    }
+
+    #[test]
+    fn test_uncoupled_systems_never_sync() {
+        let attractor = AttractorKind::Lorenz.build(&[42; 32]);
+        let config = SyncConfig {
+            max_steps: 5_000,
+            ..SyncConfig::default()
+        };
+
+        let (status, _, _) = run_sync(
+            attractor.as_ref(),
+            vec![-10.0, -7.0, 35.0],
+            vec![1.0, 1.0, 2.0],
+            false,
+            config,
+        );
+        assert_eq!(status, SyncStatus::Failed);
+    }
+
+    #[test]
+    fn test_detector_needs_a_full_window() {
+        let mut detector = SyncDetector::new(SyncConfig {
+            window: 10,
+            ..SyncConfig::default()
+        });
+
+        for _ in 0..9 {
+            assert_eq!(detector.observe(0.0, 1.0), SyncStatus::Converging);
+        }
+        assert_eq!(detector.observe(0.0, 1.0), SyncStatus::Synced);
+
+        // one bad step is enough to push the RMS error back out of tolerance
+        assert_eq!(detector.observe(1.0, 1.0), SyncStatus::Converging);
+    }
+
+    #[test]
+    fn test_detector_times_out() {
+        let mut detector = SyncDetector::new(SyncConfig {
+            timeout: Duration::ZERO,
+            ..SyncConfig::default()
+        });
+
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(detector.observe(1.0, 1.0), SyncStatus::Failed);
+    }
+
+    #[test]
+    fn test_perturb_stays_within_magnitude() {
+        let state = vec![1.0, -2.0, 30.0, 4.0];
+        for _ in 0..100 {
+            let perturbed = perturb(&state, 0.5);
+            assert_ne!(perturbed, state);
+            assert_eq!(perturbed.len(), state.len());
+            for (p, s) in perturbed.iter().zip(state.iter()) {
+                assert!((p - s).abs() < 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_drive_batch_round_trip() {
+        let states = vec![
+            vec![1.5, -2.0, 30.25],
+            vec![-0.125, 4.0, 12.0],
+            vec![7.0, 8.0, 9.0],
+        ];
+
+        let batch = DriveBatch::from_states(42, &states);
+        let bytes = batch.to_bytes();
+        assert_eq!(bytes.len(), 13 + 3 * 8);
+
+        let decoded = DriveBatch::from_bytes(&bytes).unwrap();
+        assert_eq!(batch, decoded);
+        assert_eq!(decoded.first_step, 42);
+        assert_eq!(decoded.x, vec![1.5, -0.125, 7.0]);
+    }
+
+    #[test]
+    fn test_drive_batch_rejects_malformed_frames() {
+        let bytes = DriveBatch::from_states(1, &vec![vec![1.0, 2.0, 3.0]; 4]).to_bytes();
+
+        assert!(DriveBatch::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(DriveBatch::from_bytes(&bytes[..10]).is_none());
+        assert!(DriveBatch::from_bytes(&[2]).is_none());
+    }
+
+    #[test]
+    fn test_sync_from_drive_residual() {
+        let attractor = AttractorKind::Lorenz.build(&[42; 32]);
+        let attractor = attractor.as_ref();
+
+        let mut driver = attractor.initial_state();
+        let mut receiver = vec![1.0, 1.0, 2.0];
+        let mut detector = SyncDetector::new(SyncConfig::default());
+
+        let mut sent = Vec::new();
+        let status = loop {
+            driver = attractor.step(&driver);
+            sent.push(driver.clone());
+
+            // the receiver only ever sees x
+            let error = (receiver[0] - driver[0]).abs();
+            receiver = attractor::driven_step(attractor, &receiver, driver[0]);
+
+            match detector.observe(error, driver[0].abs()) {
+                SyncStatus::Converging => (),
+                status => break status,
+            }
+        };
+        assert_eq!(status, SyncStatus::Synced);
+
+        let quantum = detector.config().quantum;
+        let step_index = detector.steps() as u64;
+        let receiver = quantize_state(&receiver, quantum);
+        let driver = quantize_state(&attractor.step(&sent[detector.steps() - 1]), quantum);
+        assert_eq!(receiver, driver);
+
+        let key = [7; 32];
+        let tag = commitment(&key, step_index, &receiver);
+        assert!(verify_commitment(&key, step_index, &driver, &tag));
+        assert!(!verify_commitment(&key, step_index + 1, &driver, &tag));
+        assert!(!verify_commitment(&[8; 32], step_index, &driver, &tag));
+
+        let mut moved = driver.clone();
+        moved[0] += quantum;
+        assert!(!verify_commitment(&key, step_index, &moved, &tag));
+    }
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!(quantize(1.13, 0.25), 1.25);
+        assert_eq!(quantize(-1.1, 0.25), -1.0);
+        assert_eq!(
+            quantize_state(&[0.1, 2.374, -3.0, 0.9], 0.25),
+            vec![0.0, 2.25, -3.0, 1.0]
+        );
+
+        // values closer together than the quantum land on the same grid point
+        assert_eq!(quantize(7.3000000001, 1e-6), quantize(7.2999999999, 1e-6));
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const TICKET_REQUEST: u8 = 14;
+pub const NEW_TICKET: u8 = 15;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// [issued at: u64] [parameter key; 32] [secret; 32]
+const CONTENTS_LEN: usize = 8 + 32 + 32;
+pub const TICKET_LEN: usize = NONCE_LEN + CONTENTS_LEN + TAG_LEN;
+
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+const ASSOCIATED_DATA: &[u8] = b"strange_cipher resumption ticket";
+
+fn hmac(key: &[u8; 32], data: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    data.iter().for_each(|d| mac.update(d));
+    mac.finalize().into_bytes().into()
+}
+
+/// What a session hands on to the ones that resume it: the key its attractor's
+/// parameters were drawn from, and a secret derived from its chaining key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resumption {
+    pub parameter_key: [u8; 32],
+    pub secret: [u8; 32],
+}
+
+impl Resumption {
+    pub fn of(parameter_key: &[u8; 32], chaining_key: &[u8; 32]) -> Resumption {
+        Resumption {
+            parameter_key: *parameter_key,
+            secret: hmac(chaining_key, &[b"resumption"]),
+        }
+    }
+
+    /// The secret a resumed session starts from, one step of a one-way ratchet on
+    /// from the ticket's, so the resumed session's keys say nothing of the one that
+    /// issued the ticket
+    pub fn ratchet(&self) -> [u8; 32] {
+        hmac(&self.secret, &[b"ratchet"])
+    }
+
+    /// Chaining key of a resumed session. Both sides' randomness goes in, so two
+    /// resumptions from the same ticket never share keys
+    pub fn resumed_key(&self, client_random: &[u8; 32], server_random: &[u8; 32]) -> [u8; 32] {
+        hmac(
+            &self.ratchet(),
+            &[b"resumed session", client_random, server_random],
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TicketError {
+    Malformed(usize),
+    /// Not sealed under this server's key, or changed since
+    Forged,
+    Expired {
+        age: Duration,
+    },
+}
+
+impl fmt::Display for TicketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TicketError::Malformed(len) => {
+                write!(f, "tickets are {} bytes long, got {}", TICKET_LEN, len)
+            }
+            TicketError::Forged => write!(f, "the ticket wasn't issued by this server"),
+            TicketError::Expired { age } => {
+                write!(
+                    f,
+                    "the ticket expired, it was issued {}s ago",
+                    age.as_secs()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TicketError {}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("The clock is set before 1970")
+        .as_secs()
+}
+
+/// Seals tickets under a key that never leaves the server, so the server keeps no
+/// state per ticket: whatever it needs to resume comes back inside the ticket. A
+/// restarted server can't open the tickets it issued before, and those clients do a
+/// full handshake again
+#[derive(Clone)]
+pub struct TicketKey {
+    key: [u8; 32],
+    lifetime: Duration,
+}
+
+impl TicketKey {
+    pub fn generate(lifetime: Duration) -> TicketKey {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        TicketKey { key, lifetime }
+    }
+
+    pub fn issue(&self, resumption: &Resumption) -> Vec<u8> {
+        self.issue_at(resumption, now())
+    }
+
+    pub fn redeem(&self, ticket: &[u8]) -> Result<Resumption, TicketError> {
+        self.redeem_at(ticket, now())
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+
+    fn issue_at(&self, resumption: &Resumption, issued_at: u64) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut contents = issued_at.to_le_bytes().to_vec();
+        contents.extend_from_slice(&resumption.parameter_key);
+        contents.extend_from_slice(&resumption.secret);
+        let sealed = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &contents,
+                    aad: ASSOCIATED_DATA,
+                },
+            )
+            .expect("ChaCha20-Poly1305 can seal a ticket");
+
+        [nonce.as_slice(), &sealed].concat()
+    }
+
+    fn redeem_at(&self, ticket: &[u8], now: u64) -> Result<Resumption, TicketError> {
+        if ticket.len() != TICKET_LEN {
+            return Err(TicketError::Malformed(ticket.len()));
+        }
+        let (nonce, sealed) = ticket.split_at(NONCE_LEN);
+        let contents = self
+            .cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: ASSOCIATED_DATA,
+                },
+            )
+            .map_err(|_| TicketError::Forged)?;
+
+        let issued_at = u64::from_le_bytes(contents[..8].try_into().unwrap());
+        let age = Duration::from_secs(now.saturating_sub(issued_at));
+        if age > self.lifetime {
+            return Err(TicketError::Expired { age });
+        }
+
+        Ok(Resumption {
+            parameter_key: contents[8..40].try_into().unwrap(),
+            secret: contents[40..72].try_into().unwrap(),
+        })
+    }
+}
+
+/// A ticket as the client keeps it, next to the secrets it stands for. Those never
+/// leave the client, so the file has to be kept as private as a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredTicket {
+    pub ticket: Vec<u8>,
+    pub resumption: Resumption,
+}
+
+impl StoredTicket {
+    // [ticket; TICKET_LEN] [parameter key; 32] [secret; 32]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.ticket.clone();
+        bytes.extend_from_slice(&self.resumption.parameter_key);
+        bytes.extend_from_slice(&self.resumption.secret);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<StoredTicket> {
+        if bytes.len() != TICKET_LEN + 64 {
+            return None;
+        }
+
+        Some(StoredTicket {
+            ticket: bytes[..TICKET_LEN].to_vec(),
+            resumption: Resumption {
+                parameter_key: bytes[TICKET_LEN..TICKET_LEN + 32].try_into().unwrap(),
+                secret: bytes[TICKET_LEN + 32..].try_into().unwrap(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn resumption() -> Resumption {
+        Resumption::of(&[1; 32], &[2; 32])
+    }
+
+    #[test]
+    fn test_ticket_restores_the_session() {
+        let key = TicketKey::generate(DEFAULT_LIFETIME);
+        let ticket = key.issue(&resumption());
+
+        assert_eq!(ticket.len(), TICKET_LEN);
+        assert_eq!(key.redeem(&ticket), Ok(resumption()));
+        // two tickets for the same session don't look alike
+        assert_ne!(key.issue(&resumption()), ticket);
+
+        let stored = StoredTicket {
+            ticket,
+            resumption: resumption(),
+        };
+        assert_eq!(StoredTicket::from_bytes(&stored.to_bytes()), Some(stored));
+    }
+
+    #[test]
+    fn test_tickets_expire() {
+        let key = TicketKey::generate(Duration::from_secs(60));
+        let ticket = key.issue_at(&resumption(), 1000);
+
+        assert!(key.redeem_at(&ticket, 1060).is_ok());
+        assert_eq!(
+            key.redeem_at(&ticket, 1061),
+            Err(TicketError::Expired {
+                age: Duration::from_secs(61)
+            })
+        );
+    }
+
+    #[test]
+    fn test_forged_tickets_are_rejected() {
+        let key = TicketKey::generate(DEFAULT_LIFETIME);
+        let mut ticket = key.issue(&resumption());
+
+        let other_server = TicketKey::generate(DEFAULT_LIFETIME);
+        assert_eq!(other_server.redeem(&ticket), Err(TicketError::Forged));
+
+        ticket[NONCE_LEN + 3] ^= 1;
+        assert_eq!(key.redeem(&ticket), Err(TicketError::Forged));
+        assert_eq!(
+            key.redeem(&ticket[1..]),
+            Err(TicketError::Malformed(TICKET_LEN - 1))
+        );
+    }
+
+    #[test]
+    fn test_resumed_keys_are_fresh() {
+        let resumption = resumption();
+        let first = resumption.resumed_key(&[3; 32], &[4; 32]);
+
+        assert_eq!(first, resumption.resumed_key(&[3; 32], &[4; 32]));
+        assert_ne!(first, resumption.resumed_key(&[3; 32], &[5; 32]));
+        assert_ne!(resumption.ratchet(), resumption.secret);
+        // resuming the resumed session moves the ratchet on again
+        let next = Resumption::of(&resumption.parameter_key, &first);
+        assert_ne!(next.ratchet(), resumption.ratchet());
+    }
+}
@@ -9,10 +9,22 @@ use std::sync::{Arc, Mutex};
 mod integration_tests {
     use super::*;
     use std::{
-        io::{BufRead, BufReader, Write},
-        process::{Child, ChildStderr, ChildStdout},
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpStream,
+        process::{Child, ExitStatus},
+        time::Instant,
     };
 
+    use rand::rngs::OsRng;
+    use strange_cipher::{
+        handshake::{self, HandshakeMode},
+        sync::DriveBatch,
+    };
+    use tungstenite::{
+        connect, protocol::frame::coding::CloseCode, stream::MaybeTlsStream, Message, WebSocket,
+    };
+    use x25519_dalek::StaticSecret;
+
     use rand::{
         distributions::{Alphanumeric, DistString},
         Rng,
@@ -21,32 +33,9 @@ mod integration_tests {
     #[test]
     #[serial] // These tests need to be ran one after the other
     fn non_concurrent() {
-        let (mut server_handle, reader, server_stderr) = setup_server();
+        let server = spawn_server(&[]);
 
         let mut sent_messages = Vec::new();
-        let decoded_messages = Arc::new(Mutex::new(Vec::new()));
-
-        let decoded_messages_clone = Arc::clone(&decoded_messages);
-        thread::spawn(move || {
-            for line in reader.lines() {
-                let line = line.expect("Failed to read line from server stdout");
-                println!("Server stdout: {}", line);
-                if line.contains("Decoded message: ") {
-                    let decoded_message = line.replace("Decoded message: ", "");
-                    decoded_messages_clone.lock().unwrap().push(decoded_message);
-                }
-            }
-        });
-
-        thread::spawn(move || {
-            for line in server_stderr.lines() {
-                let line = line.expect("Failed to read line from server stderr");
-                println!("Server stderr: {}", line);
-            }
-        });
-
-        thread::sleep(Duration::from_secs(1));
-
         for _ in 0..100 {
             let random_message = Alphanumeric.sample_string(
                 &mut rand::thread_rng(),
@@ -60,10 +49,8 @@ mod integration_tests {
             client_thread.join().expect("Couldn't join thread");
         }
 
-        server_handle.kill().expect("Failed to kill the server");
-        server_handle.wait().expect("Failed to wait for the server");
-
-        let decoded_messages = decoded_messages.lock().unwrap();
+        server.wait_for_decoded(sent_messages.len());
+        let decoded_messages = server.decoded();
         for (sent_message, decoded_message) in sent_messages.iter().zip(decoded_messages.iter()) {
             assert_eq!(sent_message, decoded_message);
         }
@@ -72,32 +59,9 @@ mod integration_tests {
     #[test]
     #[serial]
     fn concurrent() {
-        let (mut server_handle, server_stdout, server_stderr) = setup_server();
+        let server = spawn_server(&[]);
 
         let mut sent_messages = Vec::new();
-        let decoded_messages = Arc::new(Mutex::new(Vec::new()));
-
-        let decoded_messages_clone = Arc::clone(&decoded_messages);
-        thread::spawn(move || {
-            for line in server_stdout.lines() {
-                let line = line.expect("Failed to read line from server stdout");
-                println!("Server stdout: {}", line);
-                if line.contains("Decoded message: ") {
-                    let decoded_message = line.replace("Decoded message: ", "");
-                    decoded_messages_clone.lock().unwrap().push(decoded_message);
-                }
-            }
-        });
-
-        thread::spawn(move || {
-            for line in server_stderr.lines() {
-                let line = line.expect("Failed to read line from server stderr");
-                println!("Server stderr: {}", line);
-            }
-        });
-
-        thread::sleep(Duration::from_secs(1));
-
         let handles: Vec<_> = (0..50)
             .map(|_| {
                 let random_message = Alphanumeric.sample_string(
@@ -115,44 +79,690 @@ mod integration_tests {
             handle.join().expect("Couldn't join thread");
         }
 
-        server_handle.kill().expect("Failed to kill the server");
-        server_handle.wait().expect("Failed to wait for the server");
+        server.wait_for_decoded(sent_messages.len());
+        let mut decoded_messages = server.decoded();
+        sent_messages.sort();
+        decoded_messages.sort();
+        assert_eq!(sent_messages, decoded_messages);
+    }
+
+    #[test]
+    #[serial]
+    fn sync_failure_is_reported() {
+        // no sync can complete in 10 steps, so every attempt fails
+        let server = spawn_server(&["--sync-max-steps", "10"]);
+        let is_failure =
+            |line: &str| line.starts_with("Sync with Client") && line.contains("failed");
+
+        let (client_status, client_stderr) = run_client_with_args("Never arrives".to_string(), &[]);
+
+        assert!(!client_status.success());
+        assert!(client_stderr
+            .iter()
+            .any(|line| line.contains("Sync failed: the server could not sync after 3 attempts")));
+        server.wait_until(|lines| lines.iter().filter(|line| is_failure(line)).count() >= 3);
+        assert_eq!(server.count(is_failure), 3);
+    }
+
+    #[test]
+    #[serial]
+    fn every_attractor_and_sync_scheme() {
+        let server = spawn_server(&[]);
+
+        let schemes = [
+            "replacement",
+            "diffusive:40",
+            "active-passive",
+            "observer:10,5,0",
+        ];
+        let mut expected = Vec::new();
+        for attractor in ["lorenz", "hyper-lorenz"] {
+            for scheme in schemes {
+                let message = format!("Synced {} with {}", attractor, scheme);
+                expected.push(message.clone());
+
+                let (client_status, _) = run_client_with_args(
+                    message,
+                    &["--attractor", attractor, "--sync-scheme", scheme],
+                );
+                assert!(client_status.success(), "{}, {}", attractor, scheme);
+            }
+        }
+        // the maps only sync by replacement
+        for attractor in ["logistic", "tent", "henon"] {
+            let message = format!("Synced {}", attractor);
+            expected.push(message.clone());
+
+            let (client_status, _) = run_client_with_args(message, &["--attractor", attractor]);
+            assert!(client_status.success(), "{}", attractor);
+        }
+
+        server.wait_for_decoded(expected.len());
+        assert_eq!(server.decoded(), expected);
+    }
+
+    #[test]
+    #[serial]
+    fn masking_cipher() {
+        let server = spawn_server(&[]);
+
+        let mut sent_messages = Vec::new();
+        for _ in 0..5 {
+            let random_message = Alphanumeric.sample_string(
+                &mut rand::thread_rng(),
+                rand::thread_rng().gen_range(10..4096),
+            );
+            sent_messages.push(random_message.clone());
+
+            let (client_status, _) = run_client_with_args(random_message, &["--cipher", "masking"]);
+            assert!(client_status.success());
+        }
+
+        server.wait_for_decoded(sent_messages.len());
+        assert_eq!(server.decoded(), sent_messages);
+    }
+
+    #[test]
+    #[serial]
+    fn envelope_encodings() {
+        let server = spawn_server(&[]);
+
+        let mut sent_messages = Vec::new();
+        for encoding in ["binary", "json", "cbor"] {
+            let random_message = Alphanumeric.sample_string(
+                &mut rand::thread_rng(),
+                rand::thread_rng().gen_range(10..4096),
+            );
+            sent_messages.push(random_message.clone());
+
+            let (client_status, _) =
+                run_client_with_args(random_message, &["--envelope", encoding]);
+            assert!(client_status.success());
+        }
+
+        server.wait_for_decoded(sent_messages.len());
+        assert_eq!(server.decoded(), sent_messages);
+    }
+
+    #[test]
+    #[serial]
+    fn replayed_messages_are_rejected() {
+        let server = spawn_server(&[]);
+        let is_replay = |line: &str| line.starts_with("Replay detected");
+
+        // every message after the first resends the first one's envelope
+        let (client_status, _) =
+            run_client_with_args("first\nsecond\nthird".to_string(), &["--replay"]);
+        assert!(client_status.success());
+
+        server.wait_for_decoded(1);
+        server.wait_until(|lines| lines.iter().filter(|line| is_replay(line)).count() >= 2);
+        assert_eq!(server.decoded(), vec!["first"]);
+        assert_eq!(server.count(is_replay), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn lattice_cipher() {
+        let server = spawn_server(&[]);
+
+        let mut sent_messages = Vec::new();
+        for _ in 0..5 {
+            let random_message = Alphanumeric.sample_string(
+                &mut rand::thread_rng(),
+                rand::thread_rng().gen_range(4096..65536),
+            );
+            sent_messages.push(random_message.clone());
+
+            let (client_status, _) = run_client_with_args(random_message, &["--cipher", "lattice"]);
+            assert!(client_status.success());
+        }
+
+        server.wait_for_decoded(sent_messages.len());
+        assert_eq!(server.decoded(), sent_messages);
+    }
+
+    #[test]
+    #[serial]
+    fn parallel_cipher() {
+        let server = spawn_server(&[]);
+
+        let mut sent_messages = Vec::new();
+        for _ in 0..5 {
+            let random_message = Alphanumeric.sample_string(
+                &mut rand::thread_rng(),
+                rand::thread_rng().gen_range(4096..65536),
+            );
+            sent_messages.push(random_message.clone());
+
+            let (client_status, _) = run_client_with_args(
+                random_message,
+                &["--cipher", "parallel", "--trajectories", "8"],
+            );
+            assert!(client_status.success());
+        }
+
+        server.wait_for_decoded(sent_messages.len());
+        assert_eq!(server.decoded(), sent_messages);
+    }
+
+    #[test]
+    #[serial]
+    fn chunked_transfer_resends_lost_chunks() {
+        let server = spawn_server(&[]);
+
+        let mut sent_messages = Vec::new();
+        for _ in 0..5 {
+            let random_message = Alphanumeric.sample_string(
+                &mut rand::thread_rng(),
+                rand::thread_rng().gen_range(200_000..1_000_000),
+            );
+            sent_messages.push(random_message.clone());
+
+            let (client_status, _) = run_client_with_args(
+                random_message,
+                &[
+                    "--cipher",
+                    "lattice",
+                    "--chunk-size",
+                    "65536",
+                    "--drop-chunk",
+                    "2",
+                ],
+            );
+            assert!(client_status.success());
+        }
+
+        server.wait_for_decoded(sent_messages.len());
+        assert_eq!(server.decoded(), sent_messages);
+    }
+
+    #[test]
+    #[serial]
+    fn file_encryption_round_trip() {
+        let dir = std::env::temp_dir().join(format!("strange-cipher-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create the scratch directory");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        let mut plaintext = vec![0u8; rand::thread_rng().gen_range(100_000..500_000)];
+        rand::thread_rng().fill(&mut plaintext[..]);
+        std::fs::write(path("plain"), &plaintext).unwrap();
+        std::fs::write(path("key"), rand::random::<[u8; 32]>()).unwrap();
+        std::fs::write(path("other key"), rand::random::<[u8; 32]>()).unwrap();
+
+        let key = ["--key-file", &path("key")];
+        let (status, _) = run_file_tool(
+            &[
+                &["encrypt", &path("plain"), &path("sealed")][..],
+                &key,
+                &["--attractor", "arnold-cat", "--trajectories", "3"],
+            ]
+            .concat(),
+        );
+        assert!(status.success());
+
+        let (status, stderr) = run_file_tool(
+            &[
+                &["encrypt", &path("plain"), &path("weak")][..],
+                &key,
+                &["--attractor", "tent"],
+            ]
+            .concat(),
+        );
+        assert!(!status.success());
+        assert!(stderr
+            .iter()
+            .any(|line| line.contains("too few dimensions")));
+
+        let (status, _) =
+            run_file_tool(&[&["decrypt", &path("sealed"), &path("opened")][..], &key].concat());
+        assert!(status.success());
+        assert_eq!(std::fs::read(path("opened")).unwrap(), plaintext);
+
+        let (status, stderr) = run_file_tool(&[
+            "decrypt",
+            &path("sealed"),
+            &path("wrong"),
+            "--key-file",
+            &path("other key"),
+        ]);
+        assert!(!status.success());
+        assert!(stderr
+            .iter()
+            .any(|line| line.contains("wrong password or key file")));
+        assert!(!dir.join("wrong").exists());
+
+        let mut sealed = std::fs::read(path("sealed")).unwrap();
+        let last = sealed.len() - 100;
+        sealed[last] ^= 1;
+        std::fs::write(path("damaged"), sealed).unwrap();
+        let (status, stderr) =
+            run_file_tool(&[&["decrypt", &path("damaged"), &path("tampered")][..], &key].concat());
+        assert!(!status.success());
+        assert!(stderr.iter().any(|line| line.contains("tampered")));
+        assert!(!dir.join("tampered").exists());
+
+        std::fs::remove_dir_all(&dir).expect("Failed to remove the scratch directory");
+    }
+
+    #[test]
+    #[serial]
+    fn resumption_tickets_survive_many_reconnects() {
+        let server = spawn_server(&[]);
+
+        let ticket =
+            std::env::temp_dir().join(format!("strange-cipher-{}.ticket", std::process::id()));
+        let ticket = ticket.to_str().unwrap();
+        let _ = std::fs::remove_file(ticket);
+
+        // the first run does a full handshake, every later one resumes from the
+        // ticket the run before it was given
+        let reconnects = 8;
+        let mut sent_messages = Vec::new();
+        for _ in 0..reconnects {
+            let message = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+            let (client_status, _) = run_client_with_args(message.clone(), &["--ticket", ticket]);
+            assert!(client_status.success());
+            sent_messages.push(message);
+        }
+
+        // a ticket the server didn't issue falls back to a full handshake
+        let mut forged = std::fs::read(ticket).unwrap();
+        forged[20] ^= 1;
+        std::fs::write(ticket, forged).unwrap();
+        let message = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let (client_status, _) = run_client_with_args(message.clone(), &["--ticket", ticket]);
+        assert!(client_status.success());
+        sent_messages.push(message);
+
+        server.wait_for_decoded(sent_messages.len());
+        let _ = std::fs::remove_file(ticket);
+
+        assert_eq!(server.decoded(), sent_messages);
+        assert_eq!(
+            server.count(|line| line.starts_with("Resumed session")),
+            reconnects - 1
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn sessions_rekey_after_enough_messages() {
+        let server = spawn_server(&[]);
+
+        let messages: Vec<String> = (0..7)
+            .map(|_| Alphanumeric.sample_string(&mut rand::thread_rng(), 12))
+            .collect();
+        // seven messages, two per epoch
+        let (client_status, _) =
+            run_client_with_args(messages.join("\n"), &["--rekey-messages", "2"]);
+        assert!(client_status.success());
+
+        // and every message in an epoch of its own
+        let (client_status, _) = run_client_with_args(
+            messages[..3].join("\n"),
+            &["--cipher", "lattice", "--rekey-bytes", "1"],
+        );
+        assert!(client_status.success());
+
+        server.wait_for_decoded(10);
+        assert_eq!(server.decoded(), [&messages[..], &messages[..3]].concat());
+        assert_eq!(server.count(|line| line.starts_with("Rekeyed with")), 3 + 2);
+    }
+
+    #[test]
+    #[serial]
+    fn pipelined_messages_share_a_sync() {
+        let server = spawn_server(&[]);
+
+        let messages: Vec<String> = (0..10)
+            .map(|_| Alphanumeric.sample_string(&mut rand::thread_rng(), 12))
+            .collect();
+        let (client_status, _) = run_client_with_args(messages.join("\n"), &["--pipeline", "4"]);
+        assert!(client_status.success());
+
+        server.wait_for_decoded(messages.len());
+        assert_eq!(server.decoded(), messages);
+        assert_eq!(
+            server.count(|line| line.starts_with("Sent: Delivered message")),
+            messages.len()
+        );
+        assert_eq!(server.count(|line| line == "Sync Complete"), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn refused_messages_are_reported_with_a_code() {
+        let server = spawn_server(&[]);
+        let refusals = |lines: &[String]| -> Vec<String> {
+            lines
+                .iter()
+                .filter_map(|line| line.strip_prefix("Sent: Refused message "))
+                .map(String::from)
+                .collect()
+        };
+
+        let (client_status, _) = run_client_with_args(
+            "first\nsecond".to_string(),
+            &["--tamper", "--pipeline", "2"],
+        );
+        assert!(client_status.success());
+        let (client_status, _) = run_client_with_args("third\nfourth".to_string(), &["--replay"]);
+        assert!(client_status.success());
+
+        server.wait_for_decoded(1);
+        server.wait_until(|lines| refusals(lines).len() >= 3);
+        assert_eq!(server.decoded(), vec!["third"]);
+        assert_eq!(
+            refusals(&server.lines()),
+            vec![
+                "0 (the MAC didn't check out)",
+                "1 (the MAC didn't check out)",
+                "0 (the message was replayed)",
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn every_cipher_is_answered_with_a_receipt() {
+        let server = spawn_server(&[]);
+
+        let ciphers: [&[&str]; 4] = [
+            &["--cipher", "masking"],
+            &["--cipher", "lattice"],
+            &["--cipher", "parallel"],
+            &["--cipher", "parallel", "--chunk-size", "2"],
+        ];
+        for args in ciphers {
+            // the client only exits once both receipts are in
+            let (client_status, _) = run_client_with_args("first\nsecond".to_string(), args);
+            assert!(client_status.success(), "{:?}", args);
+        }
+
+        server.wait_for_decoded(8);
+        let receipts: Vec<String> = server
+            .lines()
+            .iter()
+            .filter_map(|line| line.strip_prefix("Sent: Delivered message "))
+            .map(String::from)
+            .collect();
+        assert_eq!(receipts, ["0", "1"].repeat(4));
+    }
+
+    #[test]
+    #[serial]
+    fn server_closes_idle_sessions_and_drains_on_shutdown() {
+        let mut server = spawn_server(&[
+            "--max-connections",
+            "1",
+            "--idle-timeout-secs",
+            "2",
+            "--ping-interval-secs",
+            "1",
+        ]);
+
+        // the first client holds the only session while its user thinks
+        let mut idle_client = spawn_client();
+        let mut idle_stdin = idle_client.stdin.take().unwrap();
+        idle_stdin.write_all(b"first\n").unwrap();
+        server.wait_for_decoded(1);
+
+        let (client_status, client_stderr) = run_client_with_args("Never sent".to_string(), &[]);
+        assert!(!client_status.success());
+        assert!(client_stderr.iter().any(|line| line.contains("503")));
+
+        // until the server gives up on it
+        server.wait_for_line("Closing the session with Client 0: idle");
+        idle_stdin.write_all(b"second\n").unwrap();
+        drop(idle_stdin);
+        assert!(!idle_client.wait().unwrap().success());
+
+        // a session open at shutdown is closed between messages
+        let mut last_client = spawn_client();
+        let mut last_stdin = last_client.stdin.take().unwrap();
+        last_stdin.write_all(b"third\n").unwrap();
+        server.wait_for_decoded(2);
+
+        let server_status = server.stop();
+        drop(last_stdin);
+        assert!(!last_client.wait().unwrap().success());
+
+        assert!(server_status.success());
+        server.wait_for_line("Closing the session with Client 2: shutting down");
+        server.wait_for_line("Server stopped");
+        assert_eq!(server.decoded(), vec!["first", "third"]);
+    }
+
+    #[test]
+    #[serial]
+    fn server_closes_sessions_that_break_the_protocol() {
+        let mut server = spawn_server(&[]);
+
+        // a request no client sends
+        let mut socket = connect_raw();
+        socket.send(Message::Binary(vec![99])).unwrap();
+        assert_eq!(close_code(&mut socket), Some(CloseCode::Protocol));
+
+        // a drive batch that skips ahead of the sync
+        let mut socket = connect_raw();
+        start_sync(&mut socket);
+        let batch = DriveBatch {
+            first_step: 5,
+            x: vec![1.0],
+        };
+        socket.send(Message::Binary(batch.to_bytes())).unwrap();
+        assert_eq!(close_code(&mut socket), Some(CloseCode::Protocol));
+
+        // neither took the server down with it
+        let (client_status, _) = run_client_with_args("still here".to_string(), &[]);
+        assert!(client_status.success());
+        server.wait_for_decoded(1);
+
+        // a client that stops in the middle of a sync only holds up a shutdown for
+        // the drain timeout
+        let mut stalled = connect_raw();
+        start_sync(&mut stalled);
+        // as does one that stops before the key exchange
+        let (_stalled_handshake, _) = connect("ws://localhost:3012/socket").expect("Can't connect");
+        let signalled = Instant::now();
+        let server_status = server.stop();
+        assert!(server_status.success());
+        assert!(signalled.elapsed() < Duration::from_secs(10));
+        assert_eq!(close_code(&mut stalled), Some(CloseCode::Away));
+
+        for expected in [
+            "Closing the session with Client 0: invalid request",
+            "Closing the session with Client 1: invalid drive batch",
+            "Closing the session with Client 3: shutting down",
+        ] {
+            server.wait_for_line(expected);
+        }
+    }
+
+    // A session with the server that only gets as far as the key exchange
+    fn connect_raw() -> WebSocket<MaybeTlsStream<TcpStream>> {
+        let (mut socket, _) = connect("ws://localhost:3012/socket").expect("Can't connect");
+        if let MaybeTlsStream::Plain(stream) = socket.get_mut() {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(15)))
+                .unwrap();
+        }
+        let static_key = StaticSecret::random_from_rng(OsRng);
+        handshake::initiate(&mut socket, HandshakeMode::NoiseXX, &static_key, None)
+            .expect("Key exchange failed");
+        socket
+    }
+
+    fn start_sync<S: Read + Write>(socket: &mut WebSocket<S>) {
+        socket.send(Message::Binary(vec![1])).unwrap();
+        match socket.read() {
+            Ok(Message::Text(text)) => assert_eq!(text, "Sync Request approved"),
+            other => panic!("Expected the sync request to be approved, got {:?}", other),
+        }
+    }
+
+    // How the server closed the session, if it did
+    fn close_code<S: Read + Write>(socket: &mut WebSocket<S>) -> Option<CloseCode> {
+        loop {
+            match socket.read() {
+                Ok(Message::Close(frame)) => return frame.map(|frame| frame.code),
+                Ok(_) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    fn decoded_message(line: &str) -> Option<String> {
+        line.strip_prefix("Decoded message from client ")
+            .and_then(|rest| rest.split_once(": "))
+            .map(|(_, message)| message.to_string())
+    }
+
+    /// A server started for one test, with every line it prints collected as it
+    /// comes. Dropping it kills the server
+    struct ServerFixture {
+        process: Child,
+        lines: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ServerFixture {
+        fn lines(&self) -> Vec<String> {
+            self.lines.lock().unwrap().clone()
+        }
+
+        fn decoded(&self) -> Vec<String> {
+            self.lines
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|line| decoded_message(line))
+                .collect()
+        }
 
-        let mut decoded_messages = decoded_messages.lock().unwrap();
-        assert_eq!(sent_messages.sort(), decoded_messages.sort());
+        fn count(&self, matches: impl Fn(&str) -> bool) -> usize {
+            self.lines
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|line| matches(line))
+                .count()
+        }
+
+        // Gives the server up to 10 seconds to print what `done` is looking for
+        fn wait_until(&self, done: impl Fn(&[String]) -> bool) -> bool {
+            for _ in 0..100 {
+                if done(&self.lines.lock().unwrap()) {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            false
+        }
+
+        // The client can exit before the server has printed what it decoded
+        fn wait_for_decoded(&self, expected: usize) {
+            self.wait_until(|lines| {
+                lines
+                    .iter()
+                    .filter(|line| decoded_message(line).is_some())
+                    .count()
+                    >= expected
+            });
+        }
+
+        fn wait_for_line(&self, expected: &str) {
+            if !self.wait_until(|lines| lines.iter().any(|line| line == expected)) {
+                panic!("The server never logged {:?}", expected);
+            }
+        }
+
+        /// Shuts the server down the way an operator would, and waits for it to exit
+        fn stop(&mut self) -> ExitStatus {
+            Command::new("kill")
+                .args(["-TERM", &self.process.id().to_string()])
+                .status()
+                .expect("Failed to signal the server");
+            self.process.wait().expect("Failed to wait for the server")
+        }
+    }
+
+    impl Drop for ServerFixture {
+        fn drop(&mut self) {
+            // a server that was already stopped has nothing left to kill
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
     }
 
-    fn setup_server() -> (Child, BufReader<ChildStdout>, BufReader<ChildStderr>) {
-        let mut server_handle = Command::new("cargo")
+    fn spawn_server(args: &[&str]) -> ServerFixture {
+        let mut process = Command::new("cargo")
             .arg("run")
             .arg("--bin")
             .arg("server")
+            .arg("--")
+            .args(args)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
             .expect("Failed to start the server");
 
-        let stdout = server_handle
+        let stdout = process
             .stdout
             .take()
             .expect("Failed to capture server stdout");
-
-        let stderr = server_handle
+        let stderr = process
             .stderr
             .take()
             .expect("Failed to capture server stderr");
 
-        let reader = BufReader::new(stdout);
-        let error_reader = BufReader::new(stderr);
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines_clone = Arc::clone(&lines);
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                println!("Server stdout: {}", line);
+                lines_clone.lock().unwrap().push(line);
+            }
+        });
+
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                println!("Server stderr: {}", line);
+            }
+        });
+
+        // time to start listening
+        thread::sleep(Duration::from_secs(1));
+
+        ServerFixture { process, lines }
+    }
+
+    fn run_client(random_message: String) {
+        let (client_status, _) = run_client_with_args(random_message, &[]);
 
-        (server_handle, reader, error_reader)
+        assert!(client_status.success());
+    }
+
+    // A client whose stdin stays open, so it waits on its user between messages
+    fn spawn_client() -> Child {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--bin")
+            .arg("client")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start the client")
     }
 
-    fn run_client(random_message: String) -> () {
+    fn run_client_with_args(random_message: String, args: &[&str]) -> (ExitStatus, Vec<String>) {
         let mut client_process = Command::new("cargo")
             .arg("run")
             .arg("--bin")
             .arg("client")
+            .arg("--")
+            .args(args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -161,35 +771,53 @@ mod integration_tests {
 
         if let Some(mut stdin) = client_process.stdin.take() {
             stdin
-                .write(random_message.as_bytes())
+                .write_all(random_message.as_bytes())
                 .expect("Failed to write to stdin");
         }
 
         if let Some(mut stdin) = client_process.stdin.take() {
             stdin
-                .write("".as_bytes())
+                .write_all("".as_bytes())
                 .expect("Failed to write to stdin");
         }
 
         let client_stdout = client_process.stdout.take().unwrap();
         let client_stderr = client_process.stderr.take().unwrap();
 
-        for line in BufReader::new(client_stdout).lines() {
-            if let Ok(line) = line {
-                println!("Client stdout: {}", line);
-            }
+        for line in BufReader::new(client_stdout).lines().map_while(Result::ok) {
+            println!("Client stdout: {}", line);
         }
 
-        for line in BufReader::new(client_stderr).lines() {
-            if let Ok(line) = line {
-                println!("Client stderr: {}", line);
-            }
+        let mut stderr_lines = Vec::new();
+        for line in BufReader::new(client_stderr).lines().map_while(Result::ok) {
+            println!("Client stderr: {}", line);
+            stderr_lines.push(line);
         }
 
         let client_status = client_process
             .wait()
             .expect("Failed to wait for the client");
 
-        assert!(client_status.success());
+        (client_status, stderr_lines)
+    }
+
+    fn run_file_tool(args: &[&str]) -> (ExitStatus, Vec<String>) {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--bin")
+            .arg("strange-cipher")
+            .arg("--")
+            .arg("file")
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .expect("Failed to run the file tool");
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        stderr
+            .lines()
+            .for_each(|line| println!("File tool stderr: {}", line));
+
+        (output.status, stderr.lines().map(String::from).collect())
     }
 }